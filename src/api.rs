@@ -0,0 +1,465 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use axum_sessions::extractors::WritableSession;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::api_keys::{ApiKeys, Caller};
+use crate::auth;
+use crate::bot::Bot;
+use crate::conversations::{Conversation, Conversations};
+use crate::export::{self, Format};
+use crate::health::Health;
+use crate::history::{Feedback, Rating};
+use crate::html::{self, Message as HTMLMsg};
+use crate::hub::{Hub, Rejected};
+use crate::metrics;
+use crate::rate_limit::RateLimiter;
+use crate::usage::Usage;
+use crate::users::Users;
+use crate::websocket::ClientCommand;
+
+pub struct AppState {
+    pub bot: Bot,
+    pub conversations: Conversations,
+    pub users: Users,
+    pub api_keys: ApiKeys,
+    pub rate_limiter: RateLimiter,
+    pub hub: Hub,
+    pub health: Health,
+}
+
+/// Error of a JSON endpoint, rendered as `{"error": "..."}`.
+#[derive(Debug)]
+pub struct ApiError(pub StatusCode, pub anyhow::Error);
+
+impl ApiError {
+    pub fn bad_request(msg: impl std::fmt::Display) -> Self {
+        Self(StatusCode::BAD_REQUEST, anyhow!("{}", msg))
+    }
+
+    pub fn not_found(msg: impl std::fmt::Display) -> Self {
+        Self(StatusCode::NOT_FOUND, anyhow!("{}", msg))
+    }
+
+    pub fn conflict(msg: impl std::fmt::Display) -> Self {
+        Self(StatusCode::CONFLICT, anyhow!("{}", msg))
+    }
+
+    pub fn unauthorized() -> Self {
+        Self(StatusCode::UNAUTHORIZED, anyhow!("Login required"))
+    }
+
+    pub fn forbidden() -> Self {
+        Self(StatusCode::FORBIDDEN, anyhow!("Admins only"))
+    }
+
+    pub fn too_many_requests(msg: impl std::fmt::Display) -> Self {
+        Self(StatusCode::TOO_MANY_REQUESTS, anyhow!("{}", msg))
+    }
+}
+
+/// The user of the session, who owns the conversations it may access.
+pub fn user(state: &AppState, session: &mut WritableSession) -> Result<String, ApiError> {
+    auth::current_user(state, session).ok_or_else(ApiError::unauthorized)
+}
+
+/// The account of the session, if it is one of `auth.admins`; see
+/// [`User::is_admin`](crate::users::User::is_admin).
+pub fn admin(state: &AppState, session: &WritableSession) -> Result<String, ApiError> {
+    let auth = &state.bot.config.auth;
+    if !auth.enabled {
+        return Err(ApiError::forbidden());
+    }
+    let user = auth::current_account(state, session).ok_or_else(ApiError::unauthorized)?;
+    if !user.is_admin(&auth.admins) {
+        return Err(ApiError::forbidden());
+    }
+    Ok(user.username)
+}
+
+impl<E: Into<anyhow::Error>> From<E> for ApiError {
+    fn from(e: E) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, e.into())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if self.0.is_server_error() {
+            metrics::error("api");
+        }
+        (self.0, Json(json!({ "error": format!("{:#}", self.1) }))).into_response()
+    }
+}
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/search", get(search))
+        .route(
+            "/conversations",
+            get(list_conversations).post(create_conversation),
+        )
+        .route(
+            "/conversations/:id",
+            get(get_conversation)
+                .patch(rename_conversation)
+                .delete(delete_conversation),
+        )
+        .route("/conversations/:id/export", get(export_conversation))
+        .route(
+            "/conversations/:id/messages/:message/edit",
+            post(edit_message),
+        )
+        .route(
+            "/conversations/:id/messages/:message/regenerate",
+            post(regenerate_message),
+        )
+        .route(
+            "/conversations/:id/messages/:message/feedback",
+            post(message_feedback),
+        )
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    q: String,
+    #[serde(default = "default_k")]
+    k: usize,
+    /// Token budget of the context, the whole budget without history by default.
+    budget: Option<u16>,
+}
+
+fn default_k() -> usize {
+    10
+}
+
+/// Upper bound of `k`, every candidate being read from disk.
+const MAX_K: usize = 50;
+
+#[derive(Debug, Serialize)]
+struct SearchResult {
+    rank: usize,
+    filename: String,
+    score: f32,
+    title: String,
+    tokens: usize,
+    fits: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResponse {
+    query: String,
+    token_budget: u16,
+    context_tokens: usize,
+    usage: Usage,
+    results: Vec<SearchResult>,
+}
+
+async fn search(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<SearchResponse>, ApiError> {
+    if query.q.trim().is_empty() {
+        return Err(ApiError::bad_request("q must not be empty"));
+    }
+    if query.k > MAX_K {
+        return Err(ApiError::bad_request(format!(
+            "k must be at most {}",
+            MAX_K
+        )));
+    }
+    let bot = &state.bot;
+    let budget = &bot.config.budget;
+    let token_budget = query
+        .budget
+        .unwrap_or(budget.max_tokens.saturating_sub(budget.response_size));
+
+    let (emb, usage) = bot.client.get_embedding(&query.q).await?;
+    caller.charge(&state, &usage);
+    let candidates = bot.embeddings.search(&emb, token_budget, query.k)?;
+
+    let context_tokens = candidates
+        .iter()
+        .filter(|c| c.fits)
+        .map(|c| c.tokens)
+        .sum();
+    let results = candidates
+        .into_iter()
+        .enumerate()
+        .map(|(idx, c)| SearchResult {
+            rank: idx + 1,
+            filename: c.filename.filename.to_string(),
+            score: c.filename.score,
+            title: c.article.title,
+            tokens: c.tokens,
+            fits: c.fits,
+        })
+        .collect();
+
+    Ok(Json(SearchResponse {
+        query: query.q,
+        token_budget,
+        context_tokens,
+        usage,
+        results,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+struct ConversationWithMessages {
+    #[serde(flatten)]
+    conversation: Conversation,
+    messages: Vec<HTMLMsg>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ConversationUpdate {
+    #[serde(default)]
+    title: String,
+}
+
+async fn list_conversations(
+    State(state): State<Arc<AppState>>,
+    Caller { user, .. }: Caller,
+) -> Result<Json<Vec<Conversation>>, ApiError> {
+    Ok(Json(state.conversations.list(&user)?))
+}
+
+async fn create_conversation(
+    State(state): State<Arc<AppState>>,
+    Caller { user, .. }: Caller,
+    update: Option<Json<ConversationUpdate>>,
+) -> Result<Json<Conversation>, ApiError> {
+    let mut conversation = state.conversations.create(&user)?;
+    if let Some(Json(update)) = update {
+        if !update.title.trim().is_empty() {
+            conversation = state
+                .conversations
+                .rename(&conversation.id, &user, &update.title)?;
+        }
+    }
+    Ok(Json(conversation))
+}
+
+#[derive(Debug, Deserialize)]
+struct BranchQuery {
+    /// Show the branch containing this message instead of the latest one.
+    branch: Option<String>,
+}
+
+async fn get_conversation(
+    State(state): State<Arc<AppState>>,
+    Caller { user, .. }: Caller,
+    Path(id): Path<String>,
+    Query(query): Query<BranchQuery>,
+) -> Result<Json<ConversationWithMessages>, ApiError> {
+    let conversation = state
+        .conversations
+        .get_owned(&id, &user)
+        .map_err(ApiError::not_found)?;
+    let mut history = state.conversations.history(&conversation)?;
+    if let Some(branch) = &query.branch {
+        history.switch(branch).map_err(ApiError::not_found)?;
+    }
+    Ok(Json(ConversationWithMessages {
+        messages: html::history_messages(&history),
+        conversation,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct EditRequest {
+    text: String,
+}
+
+/// 400 for commands the history can't apply, 500 for other failures.
+fn command_error(e: anyhow::Error) -> ApiError {
+    if e.is::<Rejected>() {
+        ApiError(StatusCode::BAD_REQUEST, e)
+    } else {
+        e.into()
+    }
+}
+
+/// Asks the edited question in a new branch and returns that branch.
+async fn edit_message(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path((id, message)): Path<(String, String)>,
+    Json(edit): Json<EditRequest>,
+) -> Result<Json<ConversationWithMessages>, ApiError> {
+    let conversation = state
+        .conversations
+        .get_owned(&id, &caller.user)
+        .map_err(ApiError::not_found)?;
+    state.conversations.touch(&id, &edit.text)?;
+    let command = ClientCommand::Edit {
+        id: message,
+        text: edit.text,
+    };
+    let outcome = state
+        .hub
+        .attach(&state, &conversation)
+        .run(command)
+        .await
+        .map_err(command_error)?;
+    if let Some(usage) = &outcome.usage {
+        caller.charge(&state, usage);
+    }
+    Ok(Json(ConversationWithMessages {
+        messages: outcome.messages,
+        conversation,
+    }))
+}
+
+/// Answers the question of an answer again in a new branch and returns that
+/// branch.
+async fn regenerate_message(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path((id, message)): Path<(String, String)>,
+) -> Result<Json<ConversationWithMessages>, ApiError> {
+    let conversation = state
+        .conversations
+        .get_owned(&id, &caller.user)
+        .map_err(ApiError::not_found)?;
+    let outcome = state
+        .hub
+        .attach(&state, &conversation)
+        .run(ClientCommand::Regenerate { id: message })
+        .await
+        .map_err(command_error)?;
+    if let Some(usage) = &outcome.usage {
+        caller.charge(&state, usage);
+    }
+    Ok(Json(ConversationWithMessages {
+        messages: outcome.messages,
+        conversation,
+    }))
+}
+
+async fn rename_conversation(
+    State(state): State<Arc<AppState>>,
+    Caller { user, .. }: Caller,
+    Path(id): Path<String>,
+    Json(update): Json<ConversationUpdate>,
+) -> Result<Json<Conversation>, ApiError> {
+    state
+        .conversations
+        .get_owned(&id, &user)
+        .map_err(ApiError::not_found)?;
+    Ok(Json(state.conversations.rename(&id, &user, &update.title)?))
+}
+
+async fn delete_conversation(
+    State(state): State<Arc<AppState>>,
+    Caller { user, .. }: Caller,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    state
+        .conversations
+        .get_owned(&id, &user)
+        .map_err(ApiError::not_found)?;
+    // Its room would store the messages still coming after the deletion
+    state.hub.close(&id).await;
+    let deleted = state
+        .hub
+        .unless_open(&id, || state.conversations.delete(&id, &user));
+    // Reopened meanwhile
+    deleted.ok_or_else(|| ApiError::conflict("The conversation is in use"))??;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    /// markdown, json or html
+    #[serde(default = "default_format")]
+    format: String,
+}
+
+fn default_format() -> String {
+    "markdown".to_string()
+}
+
+async fn export_conversation(
+    State(state): State<Arc<AppState>>,
+    Caller { user, .. }: Caller,
+    Path(id): Path<String>,
+    Query(query): Query<ExportQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let format: Format = query.format.parse().map_err(ApiError::bad_request)?;
+    let conversation = state
+        .conversations
+        .get_owned(&id, &user)
+        .map_err(ApiError::not_found)?;
+    let history = state.conversations.history(&conversation)?;
+    let base = base_url(&headers);
+    let body = export::export(&conversation, &history, format, base.as_deref())?;
+    let disposition = format!(
+        "attachment; filename=\"{}.{}\"",
+        conversation.id,
+        format.extension()
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// The URL of the server as requested, for links in downloaded files.
+fn base_url(headers: &HeaderMap) -> Option<String> {
+    let host = headers.get(header::HOST)?.to_str().ok()?;
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|proto| proto.to_str().ok())
+        .unwrap_or("http");
+    Some(format!("{}://{}", scheme, host))
+}
+
+#[derive(Debug, Deserialize)]
+struct FeedbackRequest {
+    rating: Rating,
+    #[serde(default)]
+    comment: Option<String>,
+}
+
+async fn message_feedback(
+    State(state): State<Arc<AppState>>,
+    Caller { user, .. }: Caller,
+    Path((id, message)): Path<(String, String)>,
+    Json(request): Json<FeedbackRequest>,
+) -> Result<Json<Feedback>, ApiError> {
+    let conversation = state
+        .conversations
+        .get_owned(&id, &user)
+        .map_err(ApiError::not_found)?;
+    let command = ClientCommand::Feedback {
+        id: message,
+        rating: request.rating,
+        comment: request.comment,
+    };
+    let feedback = state
+        .hub
+        .attach(&state, &conversation)
+        .run(command)
+        .await
+        .map_err(command_error)?
+        .feedback
+        .ok_or_else(|| anyhow!("No feedback was recorded"))?;
+    Ok(Json(feedback))
+}
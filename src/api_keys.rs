@@ -0,0 +1,468 @@
+//! API keys for programmatic access to `/api` and `/v1`, kept in the JSON
+//! file `paths.api_keys`.
+//!
+//! A key is sent as `Authorization: Bearer gptrs_<id>_<secret>`; only a
+//! SHA-256 hash of the secret is stored. Every key acts for a user and may
+//! have daily quotas on requests and tokens, counted per UTC day. The tokens
+//! of a request are only known once it is answered, so the request reaching
+//! the token quota is answered in full and the following ones are refused.
+//! Keys are checked before `[rate_limit]`, so requests it refuses count too.
+//!
+//! The server sees the keys created or revoked by `gpt-rs api-key` as soon
+//! as the file changes. It counts the usage of the keys in memory and writes
+//! it to `paths.api_key_usage` every `FLUSH_INTERVAL`, so requests don't wait
+//! for the disk.
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, State},
+    http::{header, request::Parts, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_sessions::extractors::WritableSession;
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
+
+use crate::api::{ApiError, AppState};
+use crate::auth;
+use crate::json_file::{JsonFile, Locked};
+use crate::usage::Usage;
+
+const TOKEN_PREFIX: &str = "gptrs_";
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    /// Public part of the token, shown by `gpt-rs api-key list`.
+    pub id: String,
+    pub name: String,
+    /// User whose conversations the key accesses.
+    pub owner: String,
+    /// Hex SHA-256 of the secret part of the token.
+    pub hash: String,
+    pub created: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked: Option<DateTime<Utc>>,
+    pub requests_per_day: Option<u64>,
+    pub tokens_per_day: Option<u64>,
+}
+
+/// Requests and tokens of a key, today and since it was created.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeyUsage {
+    /// `%Y-%m-%d` in UTC; the daily counters restart on another day.
+    pub day: String,
+    pub requests: u64,
+    pub tokens: u64,
+    pub total_requests: u64,
+    pub total_tokens: u64,
+}
+
+impl KeyUsage {
+    fn roll(&mut self, day: &str) {
+        if self.day != day {
+            self.day = day.to_string();
+            self.requests = 0;
+            self.tokens = 0;
+        }
+    }
+}
+
+/// Limits of a new key; `None` is unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Quotas {
+    pub requests_per_day: Option<u64>,
+    pub tokens_per_day: Option<u64>,
+}
+
+pub struct ApiKeys {
+    keys: JsonFile<Vec<ApiKey>>,
+    usage_path: PathBuf,
+    usage: Mutex<Counters>,
+}
+
+/// Usage of the keys by id.
+#[derive(Default)]
+struct Counters {
+    keys: HashMap<String, KeyUsage>,
+    /// Whether they changed since written.
+    changed: bool,
+}
+
+fn today() -> String {
+    Utc::now().format("%Y-%m-%d").to_string()
+}
+
+fn hash(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+fn random_string(len: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+impl ApiKeys {
+    /// Loads the keys of `path` and their usage of `usage_path`, which are
+    /// created on the first change.
+    pub fn open(path: &Path, usage_path: &Path) -> Result<Self> {
+        let usage = if usage_path.exists() {
+            let file = File::open(usage_path)?;
+            // Losing the counters is better than refusing to start
+            serde_json::from_reader(BufReader::new(file)).unwrap_or_else(|e| {
+                warn!(
+                    "Couldn't read API key usage from {}, counting from zero: {}",
+                    usage_path.display(),
+                    e
+                );
+                HashMap::new()
+            })
+        } else {
+            HashMap::new()
+        };
+        Ok(Self {
+            keys: JsonFile::open(path, "API keys")?,
+            usage_path: usage_path.to_path_buf(),
+            usage: Mutex::new(Counters {
+                keys: usage,
+                changed: false,
+            }),
+        })
+    }
+
+    fn keys(&self) -> Locked<'_, Vec<ApiKey>> {
+        self.keys.lock()
+    }
+
+    fn counters(&self) -> std::sync::MutexGuard<'_, Counters> {
+        self.usage.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn list(&self) -> Vec<ApiKey> {
+        self.keys().clone()
+    }
+
+    /// Usage of key `id` as of today.
+    pub fn usage(&self, id: &str) -> KeyUsage {
+        let mut usage = self.counters().keys.get(id).cloned().unwrap_or_default();
+        usage.roll(&today());
+        usage
+    }
+
+    /// Writes the usage of the keys, if it changed.
+    pub fn flush(&self) -> Result<()> {
+        let json = {
+            let mut counters = self.counters();
+            if !counters.changed {
+                return Ok(());
+            }
+            counters.changed = false;
+            serde_json::to_vec_pretty(&counters.keys)?
+        };
+        let tmp = self.usage_path.with_extension("tmp");
+        let written = fs::write(&tmp, json).and_then(|()| fs::rename(&tmp, &self.usage_path));
+        if written.is_err() {
+            self.counters().changed = true;
+        }
+        Ok(written?)
+    }
+
+    /// Creates a key for `owner` and returns it with its token, which can't
+    /// be recovered later.
+    pub fn create(&self, name: &str, owner: &str, quotas: Quotas) -> Result<(ApiKey, String)> {
+        let id = random_string(8);
+        let secret = random_string(32);
+        let key = ApiKey {
+            id: id.clone(),
+            name: name.to_string(),
+            owner: owner.to_string(),
+            hash: hash(&secret),
+            created: Utc::now(),
+            revoked: None,
+            requests_per_day: quotas.requests_per_day,
+            tokens_per_day: quotas.tokens_per_day,
+        };
+        let mut keys = self.keys();
+        keys.push(key.clone());
+        keys.save()?;
+        Ok((key, format!("{}{}_{}", TOKEN_PREFIX, id, secret)))
+    }
+
+    pub fn revoke(&self, id: &str) -> Result<ApiKey> {
+        let mut keys = self.keys();
+        let key = keys
+            .iter_mut()
+            .find(|k| k.id == id)
+            .ok_or_else(|| anyhow!("No API key {}", id))?;
+        if key.revoked.is_some() {
+            bail!("API key {} is already revoked", id);
+        }
+        key.revoked = Some(Utc::now());
+        let key = key.clone();
+        keys.save()?;
+        Ok(key)
+    }
+
+    /// Checks `token` and the quotas of its key, and counts the request.
+    fn authenticate(&self, token: &str) -> Result<ApiKey, ApiError> {
+        let unauthorized = || ApiError(StatusCode::UNAUTHORIZED, anyhow!("Invalid API key"));
+        let (id, secret) = token
+            .strip_prefix(TOKEN_PREFIX)
+            .and_then(|t| t.split_once('_'))
+            .ok_or_else(unauthorized)?;
+        let key = self
+            .keys()
+            .iter()
+            .find(|k| k.id == id && k.revoked.is_none())
+            .filter(|k| k.hash == hash(secret))
+            .cloned()
+            .ok_or_else(unauthorized)?;
+
+        let mut counters = self.counters();
+        let usage = counters.keys.entry(key.id.clone()).or_default();
+        usage.roll(&today());
+        if key
+            .requests_per_day
+            .is_some_and(|max| usage.requests >= max)
+        {
+            return Err(ApiError::too_many_requests(format!(
+                "API key {} has used its {} requests of today",
+                key.id, usage.requests
+            )));
+        }
+        if key.tokens_per_day.is_some_and(|max| usage.tokens >= max) {
+            return Err(ApiError::too_many_requests(format!(
+                "API key {} has used its {} tokens of today",
+                key.id, usage.tokens
+            )));
+        }
+        usage.requests += 1;
+        usage.total_requests += 1;
+        counters.changed = true;
+        Ok(key)
+    }
+
+    /// Counts the tokens used for a request made with key `id`. A request may
+    /// take the key over its token quota; the next one is refused.
+    pub fn charge(&self, id: &str, usage: &Usage) {
+        let mut counters = self.counters();
+        let key = counters.keys.entry(id.to_string()).or_default();
+        key.roll(&today());
+        key.tokens += usage.total();
+        key.total_tokens += usage.total();
+        counters.changed = true;
+    }
+}
+
+/// Writes the usage of the API keys every `FLUSH_INTERVAL`.
+pub fn spawn_flush(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let state = state.clone();
+            match tokio::task::spawn_blocking(move || state.api_keys.flush()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Couldn't write the usage of the API keys: {:#}", e),
+                Err(e) => error!("Writing the usage of the API keys panicked: {}", e),
+            }
+        }
+    });
+}
+
+/// Authenticates requests with an `Authorization: Bearer` header; the key is
+/// then available to `Caller`. Requests without the header go on to the
+/// session login.
+pub async fn authenticate<B>(
+    State(state): State<Arc<AppState>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    if let Some(token) = token {
+        match state.api_keys.authenticate(token) {
+            Ok(key) => {
+                request.extensions_mut().insert(key);
+            }
+            Err(e) => return e.into_response(),
+        }
+    }
+    next.run(request).await
+}
+
+/// Who a request acts for: the owner of its API key, or the user of its
+/// session.
+pub struct Caller {
+    pub user: String,
+    /// Id of the API key of the request.
+    pub key: Option<String>,
+}
+
+impl Caller {
+    /// Name of the caller in the usage ledger: the API key id, or the user
+    /// of the session.
+    pub fn name(&self) -> String {
+        match &self.key {
+            Some(key) => format!("key:{}", key),
+            None => format!("user:{}", self.user),
+        }
+    }
+
+    /// Counts `usage` against the API key of the request, if any.
+    pub fn charge(&self, state: &AppState, usage: &Usage) {
+        if let Some(key) = &self.key {
+            state.api_keys.charge(key, usage);
+        }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Caller {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(key) = parts.extensions.get::<ApiKey>() {
+            return Ok(Caller {
+                user: key.owner.clone(),
+                key: Some(key.id.clone()),
+            });
+        }
+        let Ok(mut session) = WritableSession::from_request_parts(parts, state).await;
+        let user = auth::current_user(state, &mut session).ok_or_else(ApiError::unauthorized)?;
+        Ok(Caller { user, key: None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_keys(dir: &Path) -> ApiKeys {
+        ApiKeys::open(&dir.join("api_keys.json"), &dir.join("api_key_usage.json")).unwrap()
+    }
+
+    fn status(result: Result<ApiKey, ApiError>) -> StatusCode {
+        result.map(|_| StatusCode::OK).unwrap_or_else(|e| e.0)
+    }
+
+    #[test]
+    fn authenticate_checks_the_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = api_keys(dir.path());
+        let (key, token) = keys.create("test", "alice", Quotas::default()).unwrap();
+        assert!(token.starts_with(&format!("gptrs_{}_", key.id)));
+        assert_eq!(keys.authenticate(&token).unwrap().owner, "alice");
+
+        let secret = token.rsplit('_').next().unwrap();
+        for token in [
+            "",
+            "gptrs_",
+            secret,
+            &format!("{}_{}", key.id, secret),
+            &format!("gptrs_{}", key.id),
+            &format!("gptrs_{}_wrong", key.id),
+            &format!("gptrs_unknown_{}", secret),
+        ] {
+            assert_eq!(
+                status(keys.authenticate(token)),
+                StatusCode::UNAUTHORIZED,
+                "{}",
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn revoked_keys_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = api_keys(dir.path());
+        let (key, token) = keys.create("test", "alice", Quotas::default()).unwrap();
+        assert!(keys.revoke(&key.id).unwrap().revoked.is_some());
+        assert_eq!(status(keys.authenticate(&token)), StatusCode::UNAUTHORIZED);
+        assert!(keys.revoke(&key.id).is_err());
+        assert!(keys.revoke("unknown").is_err());
+    }
+
+    #[test]
+    fn quotas_refuse_the_requests_after_the_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = api_keys(dir.path());
+        let quotas = Quotas {
+            requests_per_day: Some(2),
+            tokens_per_day: None,
+        };
+        let (_, token) = keys.create("requests", "alice", quotas).unwrap();
+        assert_eq!(status(keys.authenticate(&token)), StatusCode::OK);
+        assert_eq!(status(keys.authenticate(&token)), StatusCode::OK);
+        assert_eq!(
+            status(keys.authenticate(&token)),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        let quotas = Quotas {
+            requests_per_day: None,
+            tokens_per_day: Some(100),
+        };
+        let (key, token) = keys.create("tokens", "alice", quotas).unwrap();
+        let usage = Usage {
+            prompt_tokens: 80,
+            completion_tokens: 30,
+            embedding_tokens: 0,
+        };
+        keys.authenticate(&token).unwrap();
+        keys.charge(&key.id, &usage);
+        assert_eq!(
+            status(keys.authenticate(&token)),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        let usage = keys.usage(&key.id);
+        assert_eq!((usage.requests, usage.tokens), (1, 110));
+        assert_eq!(usage.day, today());
+    }
+
+    #[test]
+    fn usage_is_kept_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = api_keys(dir.path());
+        let (key, token) = keys.create("test", "alice", Quotas::default()).unwrap();
+        keys.authenticate(&token).unwrap();
+        keys.flush().unwrap();
+
+        let usage = api_keys(dir.path()).usage(&key.id);
+        assert_eq!((usage.requests, usage.total_requests), (1, 1));
+    }
+
+    #[test]
+    fn unreadable_usage_starts_from_zero() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("api_key_usage.json"), "{not json").unwrap();
+        let keys = api_keys(dir.path());
+        let (key, token) = keys.create("test", "alice", Quotas::default()).unwrap();
+        keys.authenticate(&token).unwrap();
+        assert_eq!(keys.usage(&key.id).requests, 1);
+    }
+}
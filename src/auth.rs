@@ -0,0 +1,284 @@
+//! Login and logout pages, and the OpenID Connect login of `[auth.oidc]`.
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::{
+    extract::{Query, State},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use axum_sessions::extractors::WritableSession;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::api::{ApiError, AppState};
+use crate::config::OidcConfig;
+use crate::html::{HtmlTemplate, LoginTemplate};
+use crate::session::{self, random_id};
+use crate::users::{OidcIdentity, User};
+
+const OIDC_STATE_KEY: &str = "oidc_state";
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/login", get(login_page).post(login))
+        .route("/logout", post(logout))
+        .route("/login/oidc", get(oidc_login))
+        .route("/login/oidc/callback", get(oidc_callback))
+}
+
+/// The user owning the conversations of the session: the logged in account
+/// with `auth.enabled`, the anonymous user of the session otherwise.
+pub fn current_user(state: &AppState, session: &mut WritableSession) -> Option<String> {
+    if !state.bot.config.auth.enabled {
+        return Some(session::user_id(session));
+    }
+    // The account may have been removed since
+    session::account(session).filter(|id| state.users.get(id).is_some())
+}
+
+/// The logged in account, `None` without `auth.enabled`.
+pub fn current_account(state: &AppState, session: &WritableSession) -> Option<User> {
+    if !state.bot.config.auth.enabled {
+        return None;
+    }
+    session::account(session).and_then(|id| state.users.get(&id))
+}
+
+/// Refuses requests without a `current_user`, e.g. when logged out with
+/// `auth.enabled`.
+pub async fn require_user<B>(
+    State(state): State<Arc<AppState>>,
+    mut session: WritableSession,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if current_user(&state, &mut session).is_none() {
+        return ApiError::unauthorized().into_response();
+    }
+    // The session layer needs it back once the response is ready
+    drop(session);
+    next.run(request).await
+}
+
+fn login_template(state: &AppState, username: &str, error: &str) -> LoginTemplate {
+    LoginTemplate {
+        username: username.to_string(),
+        error: error.to_string(),
+        oidc: state.bot.config.auth.oidc.as_ref().map(|o| o.name.clone()),
+    }
+}
+
+fn login_error(state: &AppState, username: &str, error: &str) -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        HtmlTemplate(login_template(state, username, error)),
+    )
+        .into_response()
+}
+
+async fn login_page(State(state): State<Arc<AppState>>, session: WritableSession) -> Response {
+    if !state.bot.config.auth.enabled || current_account(&state, &session).is_some() {
+        return Redirect::to("/").into_response();
+    }
+    HtmlTemplate(login_template(&state, "", "")).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+async fn login(
+    State(state): State<Arc<AppState>>,
+    mut session: WritableSession,
+    Form(credentials): Form<Credentials>,
+) -> Response {
+    // Hashing the password would hold up the other requests of the worker
+    let verify = {
+        let (state, username) = (state.clone(), credentials.username.clone());
+        let password = credentials.password;
+        tokio::task::spawn_blocking(move || state.users.verify(&username, &password))
+    };
+    let Some(user) = verify.await.ok().flatten() else {
+        warn!("Failed login of {}", credentials.username);
+        return login_error(&state, &credentials.username, "Wrong username or password");
+    };
+    if let Err(e) = session::login(&state.conversations, &mut session, &user) {
+        warn!("Couldn't log in {}: {:#}", user.username, e);
+        return login_error(
+            &state,
+            &credentials.username,
+            "Login failed, please try again",
+        );
+    }
+    info!("{} logged in", user.username);
+    Redirect::to("/").into_response()
+}
+
+async fn logout(mut session: WritableSession) -> Redirect {
+    session::logout(&mut session);
+    Redirect::to("/login")
+}
+
+/// Endpoints of the provider, from its discovery document.
+#[derive(Debug, Deserialize)]
+struct Provider {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserInfo {
+    sub: String,
+    preferred_username: Option<String>,
+    email: Option<String>,
+}
+
+async fn discover(oidc: &OidcConfig) -> Result<Provider> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        oidc.issuer.trim_end_matches('/')
+    );
+    let provider = reqwest::get(&url)
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .with_context(|| format!("Invalid discovery document {}", url))?;
+    Ok(provider)
+}
+
+async fn oidc_login(State(state): State<Arc<AppState>>, mut session: WritableSession) -> Response {
+    let Some(oidc) = &state.bot.config.auth.oidc else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let provider = match discover(oidc).await {
+        Ok(provider) => provider,
+        Err(e) => {
+            warn!("OIDC discovery failed: {:#}", e);
+            return login_error(&state, "", "The login provider is not available");
+        }
+    };
+    let csrf = random_id();
+    if let Err(e) = session.insert(OIDC_STATE_KEY, &csrf) {
+        warn!("Couldn't store OIDC state in session: {}", e);
+        return login_error(&state, "", "Login failed, please try again");
+    }
+    let url = reqwest::Url::parse_with_params(
+        &provider.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", oidc.client_id.as_str()),
+            ("redirect_uri", oidc.redirect_url.as_str()),
+            ("scope", oidc.scopes.as_str()),
+            ("state", csrf.as_str()),
+        ],
+    );
+    match url {
+        Ok(url) => Redirect::to(url.as_str()).into_response(),
+        Err(e) => {
+            warn!("Invalid OIDC authorization endpoint: {}", e);
+            login_error(&state, "", "The login provider is not available")
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Callback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+async fn oidc_callback(
+    State(state): State<Arc<AppState>>,
+    mut session: WritableSession,
+    Query(callback): Query<Callback>,
+) -> Response {
+    let Some(oidc) = &state.bot.config.auth.oidc else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let expected = session.get::<String>(OIDC_STATE_KEY);
+    session.remove(OIDC_STATE_KEY);
+    let user = match oidc_user(&state, oidc, expected, callback).await {
+        Ok(user) => user,
+        Err(e) => {
+            warn!("OIDC login failed: {:#}", e);
+            return login_error(&state, "", "Login failed, please try again");
+        }
+    };
+    if let Err(e) = session::login(&state.conversations, &mut session, &user) {
+        warn!("Couldn't log in {}: {:#}", user.username, e);
+        return login_error(&state, "", "Login failed, please try again");
+    }
+    info!("{} logged in with OIDC", user.username);
+    Redirect::to("/").into_response()
+}
+
+/// Exchanges the code of the callback for the user it identifies.
+async fn oidc_user(
+    state: &AppState,
+    oidc: &OidcConfig,
+    expected: Option<String>,
+    callback: Callback,
+) -> Result<User> {
+    if let Some(error) = callback.error {
+        bail!("Provider returned {}", error);
+    }
+    if expected.is_none() || callback.state != expected {
+        bail!("State doesn't match the one of the session");
+    }
+    let code = callback
+        .code
+        .ok_or_else(|| anyhow!("No code in callback"))?;
+
+    let provider = discover(oidc).await?;
+    let client = reqwest::Client::new();
+    let mut request = client.post(&provider.token_endpoint).form(&[
+        ("grant_type", "authorization_code"),
+        ("code", code.as_str()),
+        ("redirect_uri", oidc.redirect_url.as_str()),
+        ("client_id", oidc.client_id.as_str()),
+    ]);
+    if let Some(secret) = &oidc.client_secret {
+        request = request.basic_auth(&oidc.client_id, Some(secret));
+    }
+    let token: TokenResponse = request
+        .send()
+        .await?
+        .error_for_status()
+        .context("Token request failed")?
+        .json()
+        .await?;
+
+    let userinfo: UserInfo = client
+        .get(&provider.userinfo_endpoint)
+        .bearer_auth(&token.access_token)
+        .send()
+        .await?
+        .error_for_status()
+        .context("Userinfo request failed")?
+        .json()
+        .await?;
+
+    let username = userinfo
+        .preferred_username
+        .or(userinfo.email)
+        .unwrap_or_else(|| userinfo.sub.clone());
+    let identity = OidcIdentity {
+        issuer: oidc.issuer.clone(),
+        subject: userinfo.sub,
+    };
+    state.users.oidc_user(identity, &username)
+}
@@ -0,0 +1,477 @@
+use anyhow::{anyhow, Result};
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionResponseMessage, Role};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::citations::Citations;
+use crate::config::{ChatMode, Config};
+use crate::embeddings::{self, ContextInfo, Embeddings, Filename};
+use crate::history::{count_tokens, History, InfoBuilder, Message, Summary};
+use crate::metrics;
+use crate::openai::{Client, Tool, ToolCall, ToolMessage};
+use crate::timer;
+use crate::trace::{Trace, TraceLog};
+use crate::usage::{Ledger, Totals, Usage};
+
+const TOOLS_PROMPT: &str = "You answer questions about the game Vallheim. When a question needs facts about the game, call search_knowledge_base and answer using the returned articles. Cite the articles you used by their source number, like [1]. Don't search for small talk or for questions about your previous answers. If the articles don't contain the answer, write 'I could not find an answer.'";
+
+const SUMMARY_PROMPT: &str = "Summarize the conversation below between a user and an assistant answering questions about the game Vallheim. Keep the facts, names and open questions needed to continue the conversation. Reply with the summary only";
+
+/// Precedes the summary of earlier messages sent to the model.
+const SUMMARY_PREFIX: &str = "Summary of the earlier conversation: ";
+
+/// Everything needed to answer a question.
+pub struct Bot {
+    pub embeddings: Embeddings,
+    pub client: Client,
+    pub config: Config,
+    pub ledger: Ledger,
+    pub traces: Option<TraceLog>,
+}
+
+/// A tool call made by the model while answering.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCallInfo<'a> {
+    pub name: String,
+    pub arguments: String,
+    pub filenames: Vec<Filename<'a>>,
+    pub tokens: usize,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchArgs {
+    query: String,
+    k: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenArticleArgs {
+    filename: String,
+}
+
+impl Bot {
+    /// Answers the last user message of `history`. The caller is responsible
+    /// for adding the returned message to the history.
+    pub async fn answer<'a>(&'a self, history: &History<'a>) -> Result<Message<'a>> {
+        self.answer_streaming(history, None).await
+    }
+
+    /// Like `answer`, but also sends the answer to `deltas` piece by piece.
+    /// Closing the receiver stops the answer, which ends with the part sent
+    /// so far and the usage up to then.
+    #[tracing::instrument(name = "turn", skip_all, fields(conversation = history.name.as_deref()))]
+    pub async fn answer_streaming<'a>(
+        &'a self,
+        history: &History<'a>,
+        deltas: Option<&UnboundedSender<String>>,
+    ) -> Result<Message<'a>> {
+        let start = Instant::now();
+        let question = history.last().map(Message::content).unwrap_or_default();
+        let mut trace = Trace::new(history.name.as_deref(), self.config.chat.mode, question);
+        let answer = self.answer_turn(history, deltas, &mut trace).await;
+        metrics::turn(start.elapsed(), answer.is_ok());
+        if answer.is_err() {
+            metrics::error("answer");
+        }
+        if let Some(traces) = &self.traces {
+            trace.finish(answer.as_ref().map(Message::content), start.elapsed());
+            if let Err(e) = traces.write(&trace) {
+                warn!("Couldn't write the trace of the turn: {:#}", e);
+            }
+        }
+        answer
+    }
+
+    async fn answer_turn<'a>(
+        &'a self,
+        history: &History<'a>,
+        deltas: Option<&UnboundedSender<String>>,
+        trace: &mut Trace,
+    ) -> Result<Message<'a>> {
+        let mut info = InfoBuilder::default();
+        let question = history
+            .messages()
+            .last()
+            .filter(|m| m.msg.role == Role::User)
+            .ok_or_else(|| anyhow!("History doesn't end with a user message"))?;
+        info.user_message_tokens(question.tokens.into());
+
+        let budget = &self.config.budget;
+        let window = history.window(budget.max_history, budget.summary_size);
+        let mut usage = Usage::default();
+        if window.summary.is_some() || !window.to_summarize.is_empty() {
+            metrics::summary_cache(window.to_summarize.is_empty());
+        }
+        let summary = if window.to_summarize.is_empty() {
+            window.summary.cloned()
+        } else {
+            let (summary, summary_usage) = timer!("summarize", trace, {
+                self.summarize(window.summary, window.to_summarize, window.start, trace)
+                    .await?
+            });
+            usage += summary_usage;
+            info.summary(Some(summary.clone()));
+            Some(summary)
+        };
+
+        let mut messages = vec![];
+        let window_messages = window.messages.iter().filter(|m| !m.cancelled);
+        let mut history_size = window_messages
+            .clone()
+            .map(|m| u64::from(m.token_count()))
+            .sum::<u64>();
+        if let Some(summary) = &summary {
+            let content = format!("{}{}", SUMMARY_PREFIX, summary.content);
+            messages.push(Message::new(Role::System, &content).msg);
+            history_size += u64::from(summary.tokens);
+            info.summarized(window.start);
+        }
+        let history_count = window_messages.clone().count();
+        messages.extend(window_messages.map(|m| m.msg.clone()));
+        info.history_count(history_count);
+        info.history_size(history_size);
+
+        let token_budget = budget
+            .max_tokens
+            .saturating_sub(u16::try_from(history_size).unwrap_or(u16::MAX))
+            .saturating_sub(budget.response_size);
+
+        let (resp, answer_usage, context_info) = match self.config.chat.mode {
+            ChatMode::Context => {
+                self.answer_with_context(question, &messages, token_budget, deltas, trace)
+                    .await?
+            }
+            ChatMode::Tools => {
+                self.answer_with_tools(&messages, token_budget, deltas, &mut info, trace)
+                    .await?
+            }
+        };
+        usage += answer_usage;
+
+        info.citations(Citations::parse(&resp.content, &context_info));
+        info.context_info(context_info);
+
+        let cost = self.config.prices.cost(
+            &usage,
+            self.client.chat_model(),
+            self.client.embedding_model(),
+        );
+        let session = history.name.as_deref().unwrap_or("anonymous");
+        // The answer is already paid for, so it isn't thrown away
+        let session_total = self
+            .ledger
+            .record(session, usage, cost)
+            .unwrap_or_else(|e| {
+                warn!("Couldn't record the usage of the turn: {:#}", e);
+                Totals::default()
+            });
+        metrics::tokens(&usage);
+        info.usage(usage).cost(cost).session_total(session_total);
+
+        Message::from_response(resp, info.build()?)
+    }
+
+    #[tracing::instrument(name = "stage", skip_all, fields(stage = "context"))]
+    async fn answer_with_context<'a>(
+        &'a self,
+        question: &Message<'a>,
+        history: &[ChatCompletionRequestMessage],
+        token_budget: u16,
+        deltas: Option<&UnboundedSender<String>>,
+        trace: &mut Trace,
+    ) -> Result<(ChatCompletionResponseMessage, Usage, ContextInfo<'a>)> {
+        let (emb, mut usage) = timer!("embedding", trace, {
+            self.client.get_embedding(question.content()).await?
+        });
+        let search_results = self.config.chat.search_results;
+        let candidates = timer!("prepare_context", trace, {
+            self.embeddings
+                .candidates(&emb, token_budget, search_results)?
+        });
+        trace.candidates(question.content(), &candidates);
+        let (context_msg, context_info) = embeddings::context_message(candidates);
+
+        let mut messages = vec![context_msg];
+        messages.extend_from_slice(history);
+        let (resp, chat_usage) = timer!("chat_completion", trace, {
+            match deltas {
+                Some(deltas) => self.client.chat_stream(&messages, deltas).await?,
+                None => self.client.chat(&messages).await?,
+            }
+        });
+        let params = json!({ "stream": deltas.is_some() });
+        let model = self.client.chat_model();
+        trace.call("answer", model, params, &messages, &resp, chat_usage);
+        usage += chat_usage;
+        Ok((resp, usage, context_info))
+    }
+
+    #[tracing::instrument(name = "stage", skip_all, fields(stage = "tools"))]
+    async fn answer_with_tools<'a>(
+        &'a self,
+        history: &[ChatCompletionRequestMessage],
+        token_budget: u16,
+        deltas: Option<&UnboundedSender<String>>,
+        info: &mut InfoBuilder<'a>,
+        trace: &mut Trace,
+    ) -> Result<(ChatCompletionResponseMessage, Usage, ContextInfo<'a>)> {
+        let tools = self.tools();
+        let mut messages = vec![ToolMessage::new("system", TOOLS_PROMPT)];
+        messages.extend(history.iter().map(ToolMessage::from));
+
+        let mut usage = Usage::default();
+        let mut calls = vec![];
+        let mut context = ContextInfo {
+            filenames: vec![],
+            size: 0,
+        };
+        // The prompt, the tool definitions and the calls of the model are sent
+        // along with the articles, as the context prompt is in the context mode
+        let definitions = serde_json::to_string(&tools)?;
+        let mut remaining = (token_budget as usize)
+            .saturating_sub(count_tokens(TOOLS_PROMPT).into())
+            .saturating_sub(count_tokens(&definitions).into());
+
+        for round in 0..=self.config.chat.max_tool_rounds {
+            // The answer is stopped by closing the receiver of the deltas
+            if deltas.is_some_and(UnboundedSender::is_closed) {
+                trace.tool_calls(&calls);
+                info.tool_calls(calls);
+                let resp = ChatCompletionResponseMessage {
+                    role: Role::Assistant,
+                    content: String::new(),
+                };
+                return Ok((resp, usage, context));
+            }
+            let allow_tools = round < self.config.chat.max_tool_rounds;
+            let (resp, chat_usage) = timer!("chat_completion", trace, {
+                self.client
+                    .chat_with_tools(&messages, &tools, allow_tools)
+                    .await?
+            });
+            let params = json!({
+                "tools": tools.iter().map(|tool| tool.name).collect::<Vec<_>>(),
+                "tool_choice": if allow_tools { "auto" } else { "none" },
+            });
+            let model = self.client.chat_model();
+            trace.call("answer", model, params, &messages, &resp, chat_usage);
+            usage += chat_usage;
+
+            if resp.tool_calls.is_empty() {
+                trace.tool_calls(&calls);
+                info.tool_calls(calls);
+                let resp = ChatCompletionResponseMessage {
+                    role: Role::Assistant,
+                    content: resp.content.unwrap_or_default(),
+                };
+                if let Some(deltas) = deltas {
+                    let _ = deltas.send(resp.content.clone());
+                }
+                return Ok((resp, usage, context));
+            }
+
+            let tool_calls = resp.tool_calls.clone();
+            let call_tokens = count_tokens(&serde_json::to_string(&tool_calls)?);
+            remaining = remaining.saturating_sub(call_tokens.into());
+            messages.push(resp);
+            for call in &tool_calls {
+                let (result, call_info) = self
+                    .run_tool(call, &mut context, &mut remaining, &mut usage)
+                    .await;
+                calls.push(call_info);
+                messages.push(ToolMessage::tool_result(call, result));
+            }
+        }
+        trace.tool_calls(&calls);
+        Err(anyhow!("Model kept calling tools"))
+    }
+
+    /// Summarizes `messages`, continuing the `previous` summary, into a
+    /// summary of the first `covers` messages of the history.
+    #[tracing::instrument(name = "stage", skip_all, fields(stage = "summary"))]
+    async fn summarize(
+        &self,
+        previous: Option<&Summary>,
+        messages: &[Message<'_>],
+        covers: usize,
+        trace: &mut Trace,
+    ) -> Result<(Summary, Usage)> {
+        let mut transcript = String::new();
+        if let Some(previous) = previous {
+            transcript.push_str(&format!("{}{}\n\n", SUMMARY_PREFIX, previous.content));
+        }
+        for message in messages {
+            transcript.push_str(&format!("{}{}\n", message.prefix(), message.content()));
+        }
+        // About 3 words per 4 tokens
+        let words = usize::from(self.config.budget.summary_size) * 3 / 4;
+        let prompt = format!("{}, in at most {} words.", SUMMARY_PROMPT, words);
+        let request = [
+            Message::new(Role::System, &prompt).msg,
+            Message::new(Role::User, &transcript).msg,
+        ];
+        let (resp, usage) = self.client.chat(&request).await?;
+        let model = self.client.chat_model();
+        trace.call("summary", model, json!({}), &request, &resp, usage);
+        let summary = Summary {
+            covers,
+            tokens: count_tokens(&resp.content),
+            content: resp.content,
+        };
+        info!(
+            "summarized {} messages in {} tokens",
+            covers, summary.tokens
+        );
+        Ok((summary, usage))
+    }
+
+    fn tools(&self) -> Vec<Tool> {
+        let mut tools = vec![Tool {
+            name: "search_knowledge_base",
+            description: "Search the articles about the game Vallheim. Returns the most relevant articles.",
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "query": {"type": "string", "description": "What to search for"},
+                    "k": {"type": "integer", "description": "Number of articles to return"},
+                },
+                "required": ["query"],
+            }),
+        }];
+        if self.config.chat.open_article {
+            tools.push(Tool {
+                name: "open_article",
+                description: "Return the full text of an article found by search_knowledge_base.",
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "filename": {"type": "string", "description": "Filename of the article"},
+                    },
+                    "required": ["filename"],
+                }),
+            });
+        }
+        tools
+    }
+
+    /// Executes a tool call and returns the text sent back to the model.
+    /// Failures are reported to the model so that it can recover.
+    #[tracing::instrument(skip_all, fields(tool = %call.function.name))]
+    async fn run_tool<'a>(
+        &'a self,
+        call: &ToolCall,
+        context: &mut ContextInfo<'a>,
+        remaining: &mut usize,
+        usage: &mut Usage,
+    ) -> (String, ToolCallInfo<'a>) {
+        let mut call_info = ToolCallInfo {
+            name: call.function.name.clone(),
+            arguments: call.function.arguments.clone(),
+            filenames: vec![],
+            tokens: 0,
+            error: None,
+        };
+        info!("tool call {}({})", call_info.name, call_info.arguments);
+
+        let result = match call.function.name.as_str() {
+            "search_knowledge_base" => {
+                self.search(&call.function.arguments, context, remaining, usage, &mut call_info)
+                    .await
+            }
+            "open_article" if self.config.chat.open_article => {
+                self.open_article(&call.function.arguments, context, remaining, &mut call_info)
+            }
+            name => Err(anyhow!("Unknown tool {}", name)),
+        };
+
+        match result {
+            Ok(result) => (result, call_info),
+            Err(e) => {
+                call_info.error = Some(e.to_string());
+                (format!("Error: {}", e), call_info)
+            }
+        }
+    }
+
+    async fn search<'a>(
+        &'a self,
+        arguments: &str,
+        context: &mut ContextInfo<'a>,
+        remaining: &mut usize,
+        usage: &mut Usage,
+        call_info: &mut ToolCallInfo<'a>,
+    ) -> Result<String> {
+        let args: SearchArgs = serde_json::from_str(arguments)?;
+        let k = args.k.unwrap_or(self.config.chat.search_results).clamp(1, 10);
+
+        let (emb, emb_usage) = self.client.get_embedding(&args.query).await?;
+        *usage += emb_usage;
+
+        let mut articles = vec![];
+        for filename in self.embeddings.top_similar(&emb).into_iter().take(k) {
+            let article = self.embeddings.article(&filename.filename)?;
+            let tokens = article.estimated_total_tokens();
+            if tokens > *remaining {
+                break;
+            }
+            *remaining -= tokens;
+            call_info.tokens += tokens;
+            articles.push(json!({
+                "source": add_source(context, &filename, tokens),
+                "filename": filename.filename,
+                "title": article.title,
+                "body": article.body,
+            }));
+            call_info.filenames.push(filename);
+        }
+        if articles.is_empty() {
+            return Ok("No articles fit into the remaining context.".to_string());
+        }
+        Ok(serde_json::to_string(&articles)?)
+    }
+
+    fn open_article<'a>(
+        &'a self,
+        arguments: &str,
+        context: &mut ContextInfo<'a>,
+        remaining: &mut usize,
+        call_info: &mut ToolCallInfo<'a>,
+    ) -> Result<String> {
+        let args: OpenArticleArgs = serde_json::from_str(arguments)?;
+        let filename = self
+            .embeddings
+            .filename(&args.filename)
+            .ok_or_else(|| anyhow!("No article {}", args.filename))?;
+        let article = self.embeddings.article(filename)?;
+        let tokens = article.estimated_total_tokens();
+        if tokens > *remaining {
+            return Err(anyhow!("Article {} doesn't fit into the context", filename));
+        }
+        *remaining -= tokens;
+        call_info.tokens += tokens;
+        let filename = Filename::new(filename, 1.0);
+        let n = add_source(context, &filename, tokens);
+        call_info.filenames.push(filename);
+        Ok(article.numbered(n))
+    }
+}
+
+/// Adds an article to the context unless it's already there and returns
+/// its citation number.
+fn add_source<'a>(context: &mut ContextInfo<'a>, filename: &Filename<'a>, tokens: usize) -> usize {
+    if let Some(idx) = context
+        .filenames
+        .iter()
+        .position(|f| f.filename == filename.filename)
+    {
+        return idx + 1;
+    }
+    context.filenames.push(filename.clone());
+    context.size += tokens;
+    context.filenames.len()
+}
+
@@ -0,0 +1,166 @@
+//! Recorded OpenAI calls, to run the web app, the CLI and evaluations
+//! without network.
+//!
+//! A cassette is a JSONL file of request/response pairs keyed by the SHA-256
+//! of the request. In `record` mode every call goes to OpenAI and its
+//! response is appended; in `replay` mode responses come from the file and a
+//! request which wasn't recorded fails.
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::config::{CassetteConfig, CassetteMode};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    kind: String,
+    request: Value,
+    response: Value,
+}
+
+pub struct Cassette {
+    mode: CassetteMode,
+    path: PathBuf,
+    /// Responses by key, the last recorded one for requests recorded twice.
+    responses: Mutex<HashMap<String, Value>>,
+}
+
+impl Cassette {
+    pub fn open(config: &CassetteConfig) -> Result<Self> {
+        let mut responses = HashMap::new();
+        if config.mode == CassetteMode::Replay || config.path.exists() {
+            let file = File::open(&config.path)
+                .with_context(|| format!("Couldn't open cassette {}", config.path.display()))?;
+            for (idx, line) in BufReader::new(file).lines().enumerate() {
+                let entry: Entry = serde_json::from_str(&line?).with_context(|| {
+                    format!("{}:{}: invalid entry", config.path.display(), idx + 1)
+                })?;
+                responses.insert(entry.key, entry.response);
+            }
+        }
+        Ok(Self {
+            mode: config.mode,
+            path: config.path.clone(),
+            responses: Mutex::new(responses),
+        })
+    }
+
+    /// Whether responses come from the cassette only.
+    pub fn replaying(&self) -> bool {
+        self.mode == CassetteMode::Replay
+    }
+
+    /// The response to `request`: the recorded one when replaying, that of
+    /// `call` otherwise. `kind` tells calls with similar requests apart.
+    pub async fn call<T, F>(&self, kind: &str, request: &impl Serialize, call: F) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: std::future::Future<Output = Result<T>>,
+    {
+        let request = serde_json::to_value(request)?;
+        let key = key(kind, &request)?;
+        match self.mode {
+            CassetteMode::Replay => {
+                let response = self.lock().get(&key).cloned().ok_or_else(|| {
+                    anyhow!(
+                        "No {} response recorded for request {} in {}, record it with openai.cassette.mode = \"record\"",
+                        kind,
+                        key,
+                        self.path.display()
+                    )
+                })?;
+                debug!("replaying {} response {}", kind, key);
+                Ok(serde_json::from_value(response)?)
+            }
+            CassetteMode::Record => {
+                let response = call.await?;
+                let entry = Entry {
+                    key,
+                    kind: kind.to_string(),
+                    request,
+                    response: serde_json::to_value(&response)?,
+                };
+                let mut line = serde_json::to_vec(&entry)?;
+                line.push(b'\n');
+
+                let mut responses = self.lock();
+                OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(&self.path)?
+                    .write_all(&line)?;
+                responses.insert(entry.key, entry.response);
+                Ok(response)
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Value>> {
+        self.responses.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Hex SHA-256 of `kind` and `request`. Objects serialize with sorted keys,
+/// so equal requests have equal keys. Whether the answer is streamed doesn't
+/// count, so an answer recorded by the web app replays in the CLI and the
+/// other way around.
+fn key(kind: &str, request: &Value) -> Result<String> {
+    let mut request = request.clone();
+    if let Some(request) = request.as_object_mut() {
+        request.remove("stream");
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(kind.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(serde_json::to_vec(&request)?);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn key_ignores_field_order_and_streaming() {
+        let request = json!({ "model": "gpt", "messages": [{ "role": "user", "content": "hi" }] });
+        let reordered =
+            json!({ "messages": [{ "content": "hi", "role": "user" }], "model": "gpt" });
+        let streamed = json!({ "model": "gpt", "messages": [{ "role": "user", "content": "hi" }], "stream": true });
+        let key = key("chat", &request).unwrap();
+        assert_eq!(key, key_of("chat", &reordered));
+        assert_eq!(key, key_of("chat", &streamed));
+        assert_eq!(key.len(), 64);
+    }
+
+    #[test]
+    fn key_depends_on_kind_and_content() {
+        let request = json!({ "model": "gpt", "input": ["hi"] });
+        let key = key("embedding", &request).unwrap();
+        assert_ne!(key, key_of("chat", &request));
+        assert_ne!(
+            key,
+            key_of("embedding", &json!({ "model": "gpt", "input": ["ho"] }))
+        );
+        assert_ne!(
+            key,
+            key_of("embedding", &json!({ "model": "other", "input": ["hi"] }))
+        );
+    }
+
+    fn key_of(kind: &str, request: &Value) -> String {
+        key(kind, request).unwrap()
+    }
+}
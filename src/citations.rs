@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+
+use crate::embeddings::ContextInfo;
+use crate::history::Info;
+
+/// Citations of context articles found in an answer. Articles are numbered
+/// from 1 in the order of `ContextInfo::filenames`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Citations {
+    /// Valid article numbers in the order of first appearance.
+    pub cited: Vec<usize>,
+    /// Numbers which don't match any article of the context.
+    pub invalid: Vec<usize>,
+}
+
+/// An article of the context of an answer, as shown to API clients and in
+/// exports.
+#[derive(Debug, Clone, Serialize)]
+pub struct Source {
+    pub index: usize,
+    pub filename: String,
+    pub score: f32,
+    pub url: String,
+    pub cited: bool,
+}
+
+/// The numbered articles of the context of an answer.
+pub fn sources(info: &Info) -> Vec<Source> {
+    info.context_info
+        .filenames
+        .iter()
+        .enumerate()
+        .map(|(idx, f)| Source {
+            index: idx + 1,
+            filename: f.filename.to_string(),
+            score: f.score,
+            url: format!("/context/{}", f.filename),
+            cited: info.citations.cited.contains(&(idx + 1)),
+        })
+        .collect()
+}
+
+impl Citations {
+    pub fn parse(text: &str, context: &ContextInfo) -> Self {
+        let mut citations = Citations::default();
+        for n in citation_numbers(text) {
+            let list = if n >= 1 && n <= context.filenames.len() {
+                &mut citations.cited
+            } else {
+                &mut citations.invalid
+            };
+            if !list.contains(&n) {
+                list.push(n);
+            }
+        }
+        citations
+    }
+
+    /// Warning shown to the user when the answer cannot be traced to the context.
+    pub fn warning(&self) -> Option<String> {
+        if !self.invalid.is_empty() {
+            let numbers: Vec<String> = self.invalid.iter().map(|n| format!("[{}]", n)).collect();
+            Some(format!("The answer cites non-existent sources {}", numbers.join(", ")))
+        } else if self.cited.is_empty() {
+            Some("The answer doesn't cite any source".to_string())
+        } else {
+            None
+        }
+    }
+}
+
+/// A part of an answer: either plain text or a citation `[n]`.
+#[derive(Debug, PartialEq)]
+pub enum Segment<'a> {
+    Text(&'a str),
+    Citation(usize),
+}
+
+/// Splits `text` into text and citations. `[1, 2]` yields two citations.
+pub fn segments(text: &str) -> Vec<Segment<'_>> {
+    let mut segments = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find('[') {
+        let Some(end) = rest[start..].find(']').map(|e| start + e) else {
+            break;
+        };
+        let numbers: Option<Vec<usize>> = rest[start + 1..end]
+            .split(',')
+            .map(|n| n.trim().parse().ok())
+            .collect();
+        match numbers {
+            Some(numbers) if !numbers.is_empty() => {
+                if start > 0 {
+                    segments.push(Segment::Text(&rest[..start]));
+                }
+                segments.extend(numbers.into_iter().map(Segment::Citation));
+            }
+            _ => segments.push(Segment::Text(&rest[..=end])),
+        }
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Text(rest));
+    }
+    segments
+}
+
+fn citation_numbers(text: &str) -> impl Iterator<Item = usize> + '_ {
+    segments(text).into_iter().filter_map(|s| match s {
+        Segment::Citation(n) => Some(n),
+        Segment::Text(_) => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::Filename;
+
+    #[test]
+    fn segments_split_citations() {
+        assert_eq!(
+            segments("Rust [1] is fast [2, 3]."),
+            vec![
+                Segment::Text("Rust "),
+                Segment::Citation(1),
+                Segment::Text(" is fast "),
+                Segment::Citation(2),
+                Segment::Citation(3),
+                Segment::Text("."),
+            ]
+        );
+    }
+
+    #[test]
+    fn segments_keep_other_brackets() {
+        assert_eq!(
+            segments("a [b] c [] d [1"),
+            vec![
+                Segment::Text("a [b]"),
+                Segment::Text(" c []"),
+                Segment::Text(" d [1")
+            ]
+        );
+        assert_eq!(segments("[7]"), vec![Segment::Citation(7)]);
+        assert_eq!(segments(""), vec![]);
+    }
+
+    #[test]
+    fn parse_sorts_out_invalid_numbers() {
+        let context = ContextInfo {
+            filenames: vec![Filename::new("a.json", 0.9), Filename::new("b.json", 0.8)],
+            size: 0,
+        };
+        let citations = Citations::parse("[2] then [1, 2], [0] and [3] [3]", &context);
+        assert_eq!(citations.cited, vec![2, 1]);
+        assert_eq!(citations.invalid, vec![0, 3]);
+        assert!(citations.warning().unwrap().contains("[0], [3]"));
+
+        let citations = Citations::parse("No sources", &context);
+        assert!(citations.cited.is_empty() && citations.invalid.is_empty());
+        assert_eq!(
+            citations.warning().as_deref(),
+            Some("The answer doesn't cite any source")
+        );
+    }
+}
@@ -1,43 +1,46 @@
 use std::io::{BufRead, stdin, Write, stdout};
 
 use anyhow::Result;
-use crate::embeddings::Embeddings;
-use crate::openai::Client;
-use crate::timer;
-//use tracing::info;
-use async_openai::types::ChatCompletionRequestMessage;
+use crate::bot::Bot;
+use crate::conversations::Conversations;
+use tracing::info;
 use crate::history::{History, Message};
-use crate::{MAX_TOKENS, RESPONSE_SIZE};
 
-use std::println as info;
 
+/// Owner of the conversations started from the command line.
+const CLI_OWNER: &str = "cli";
 
-pub async fn cli_chat_loop(
-    embeddings: &Embeddings,
-    client: &Client,
-) {
+pub async fn cli_chat_loop(bot: &Bot, conversations: &Conversations) -> Result<()> {
     let stdin = stdin();
     let lines = stdin.lock().lines(); // Create a handle to stdin and a stream of lines
-    let mut history = History::new();
+    let mut history = new_history(conversations)?;
 
     cli_prompt();
 
-    for line_result in lines {
-        if let Ok(line) = line_result { // If the line was read successfully...
-            let msg = line.trim();
-            if line == "reset" {
-                history = History::new();
-                println!("History was reset");
-            } else {
-                let response = cli_process_message(&msg, embeddings, client, &mut history).await;
-                let r = response.unwrap();
-                println!("");
-                println!("{}", r);
-                println!("");
+    for line in lines.map_while(Result::ok) { // Stop on the first line that cannot be read
+        let msg = line.trim();
+        if line == "reset" {
+            history = new_history(conversations)?;
+            println!("History was reset");
+        } else {
+            match cli_process_message(msg, bot, &mut history).await {
+                Ok(r) => {
+                    println!();
+                    println!("{}", r);
+                    println!();
+                }
+                // The question stays in the history, as in the web app
+                Err(e) => eprintln!("Couldn't answer: {:#}", e),
             }
-            cli_prompt();
         }
+        cli_prompt();
     }
+    Ok(())
+}
+
+fn new_history<'a>(conversations: &Conversations) -> Result<History<'a>> {
+    let conversation = conversations.create(CLI_OWNER)?;
+    conversations.history(&conversation)
 }
 
 fn cli_prompt() {
@@ -45,36 +48,35 @@ fn cli_prompt() {
     stdout().flush().unwrap();
 }
 
-async fn cli_process_message(
+async fn cli_process_message<'a>(
     msg: &str,
-    embeddings: &Embeddings,
-    client: &Client,
-    history: &mut History<'_>,
+    bot: &'a Bot,
+    history: &mut History<'a>,
 ) -> Result<String> {
     let user_msg = Message::user(msg)?;
 
     history.user(user_msg.clone());
 
-    let pruned_messages = history.prune_history();
-
-    let history_size = pruned_messages.iter().map(|m| m.tokens).sum::<u16>();
-
-    let emb = timer!("get_embedding", {
-        client.get_embedding(msg).await?
-    });
-    let (context_msg, _context_info) = timer!("prepare_context", {
-        embeddings.prepare_context(&emb, MAX_TOKENS - history_size - RESPONSE_SIZE)?
-    });
-
-    let mut messages = vec![context_msg];
-    messages.extend_from_slice(
-        &pruned_messages
-            .iter()
-            .map(|m| m.msg.clone())
-            .collect::<Vec<ChatCompletionRequestMessage>>(),
-    );
-    let resp = timer!("openai chat completion", {
-        client.chat(&messages).await?
-    });
-    Ok(resp.content)
+    let resp_msg = bot.answer(history).await?;
+    if let Some(info) = &resp_msg.info {
+        info!(
+            "usage: {} tokens, ${:.5}; session: {} tokens, ${:.5}",
+            info.usage.total(),
+            info.cost,
+            info.session_total.usage.total(),
+            info.session_total.cost
+        );
+    }
+    let mut content = resp_msg.content().to_string();
+    if let Some(info) = &resp_msg.info {
+        for n in &info.citations.cited {
+            let source = &info.context_info.filenames[n - 1];
+            content.push_str(&format!("\n[{}] /context/{}", n, source.filename));
+        }
+        if let Some(warning) = info.citations.warning() {
+            content.push_str(&format!("\n(!) {}", warning));
+        }
+    }
+    history.assistant(resp_msg);
+    Ok(content)
 }
@@ -0,0 +1,203 @@
+//! OpenAI-compatible `/v1/chat/completions` endpoint answering with the same
+//! retrieval + chat flow as the websocket. The articles used for the answer
+//! are returned in the `sources` extension field.
+use std::{convert::Infallible, sync::Arc};
+
+use async_openai::types::Role;
+use axum::{
+    extract::State,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::post,
+    Json, Router,
+};
+use chrono::Utc;
+use futures::StreamExt;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::info;
+
+use crate::api::{ApiError, AppState};
+use crate::api_keys::Caller;
+use crate::citations::{self, Source};
+use crate::history::{History, Message};
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new().route("/chat/completions", post(chat_completions))
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatRequest {
+    messages: Vec<RequestMessage>,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct RequestMessage {
+    role: String,
+    /// Either a string or an array of content parts.
+    #[serde(default)]
+    content: Value,
+}
+
+impl RequestMessage {
+    fn text(&self) -> String {
+        match &self.content {
+            Value::String(text) => text.clone(),
+            Value::Array(parts) => parts
+                .iter()
+                .filter_map(|part| part.get("text").and_then(Value::as_str))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => String::new(),
+        }
+    }
+}
+
+fn build_history<'a>(request: &ChatRequest, caller: &Caller) -> Result<History<'a>, ApiError> {
+    let mut history = History::in_memory();
+    // Books the usage of the request to the caller in the ledger
+    history.name = Some(caller.name());
+    for message in &request.messages {
+        let role = match message.role.as_str() {
+            "system" | "developer" => Role::System,
+            "user" => Role::User,
+            "assistant" => Role::Assistant,
+            _ => continue,
+        };
+        history.push(Message::new(role, &message.text()));
+    }
+    match history.messages().last() {
+        Some(last) if last.msg.role == Role::User => Ok(history),
+        _ => Err(ApiError::bad_request("the last message must come from the user")),
+    }
+}
+
+fn completion_id() -> String {
+    let id: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect();
+    format!("chatcmpl-{}", id)
+}
+
+fn sources(message: &Message) -> Vec<Source> {
+    message.info.as_ref().map(citations::sources).unwrap_or_default()
+}
+
+fn usage(message: &Message) -> Value {
+    let usage = message.info.as_ref().map(|i| i.usage).unwrap_or_default();
+    json!({
+        "prompt_tokens": usage.prompt_tokens,
+        "completion_tokens": usage.completion_tokens,
+        "total_tokens": usage.prompt_tokens + usage.completion_tokens,
+        "embedding_tokens": usage.embedding_tokens,
+    })
+}
+
+async fn chat_completions(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Json(request): Json<ChatRequest>,
+) -> Result<Response, ApiError> {
+    let history = build_history(&request, &caller)?;
+    let id = completion_id();
+    let created = Utc::now().timestamp();
+
+    if !request.stream {
+        let bot = &state.bot;
+        let answer = bot.answer(&history).await?;
+        if let Some(info) = &answer.info {
+            caller.charge(&state, &info.usage);
+        }
+        return Ok(Json(json!({
+            "id": id,
+            "object": "chat.completion",
+            "created": created,
+            "model": bot.client.chat_model(),
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": answer.content()},
+                "finish_reason": "stop",
+            }],
+            "usage": usage(&answer),
+            "sources": sources(&answer),
+        }))
+        .into_response());
+    }
+
+    let (events, rx) = mpsc::unbounded_channel::<Event>();
+    tokio::spawn(async move {
+        let bot = &state.bot;
+        let model = bot.client.chat_model();
+        let chunk = |delta: Value, finish_reason: Value| {
+            json!({
+                "id": id,
+                "object": "chat.completion.chunk",
+                "created": created,
+                "model": model,
+                "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+            })
+        };
+        // Sending fails only when the client has gone away
+        let send = |data: Value| {
+            let _ = events.send(Event::default().data(data.to_string()));
+        };
+
+        send(chunk(json!({"role": "assistant"}), Value::Null));
+
+        let (deltas_tx, mut deltas_rx) = mpsc::unbounded_channel::<String>();
+        let answer = async {
+            let answer = bot.answer_streaming(&history, Some(&deltas_tx)).await;
+            drop(deltas_tx);
+            answer
+        };
+        // The answer is stopped once the client has gone away, so that it
+        // isn't written and paid for in vain
+        let forward = async {
+            let mut stopped = false;
+            loop {
+                tokio::select! {
+                    delta = deltas_rx.recv() => match delta {
+                        Some(delta) => send(chunk(json!({ "content": delta }), Value::Null)),
+                        None => break,
+                    },
+                    _ = events.closed(), if !stopped => {
+                        info!("Client of completion {} has gone away", id);
+                        deltas_rx.close();
+                        stopped = true;
+                    }
+                }
+            }
+        };
+        let (answer, _) = tokio::join!(answer, forward);
+
+        match answer {
+            Ok(answer) => {
+                if let Some(info) = &answer.info {
+                    caller.charge(&state, &info.usage);
+                }
+                let mut last = chunk(json!({}), json!("stop"));
+                last["usage"] = usage(&answer);
+                last["sources"] = json!(sources(&answer));
+                send(last);
+            }
+            Err(e) => {
+                send(json!({"error": {"message": format!("{:#}", e), "type": "server_error"}}));
+            }
+        }
+        let _ = events.send(Event::default().data("[DONE]"));
+    });
+
+    let stream = UnboundedReceiverStream::new(rx).map(Ok::<_, Infallible>);
+    Ok(Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
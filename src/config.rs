@@ -0,0 +1,808 @@
+use crate::usage::Prices;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use toml::Value;
+use tracing::warn;
+
+/// Prefix of environment variables overriding config values.
+/// `GPT_RS_SERVER__LISTEN=127.0.0.1:8080` overrides `server.listen`.
+pub const ENV_PREFIX: &str = "GPT_RS_";
+pub const DEFAULT_CONFIG_FILE: &str = "./gpt-rs.toml";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub openai: OpenAIConfig,
+    pub paths: PathsConfig,
+    pub budget: BudgetConfig,
+    pub chat: ChatConfig,
+    pub storage: StorageConfig,
+    pub retention: RetentionConfig,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub trace: TraceConfig,
+    /// Prices per 1000 tokens, keyed by model name.
+    pub prices: Prices,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: String,
+    /// Secret used to sign session cookies, at least 64 bytes.
+    /// A random one is generated on startup if it is not set.
+    pub session_secret: Option<String>,
+    /// Websockets are pinged after this long without a frame from the client.
+    pub heartbeat_seconds: u64,
+    /// Websockets without any frame, pongs included, for this long are closed.
+    pub idle_timeout_seconds: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenAIConfig {
+    /// Falls back to the OPENAI_API_KEY environment variable.
+    pub api_key: Option<String>,
+    pub chat_model: String,
+    pub embedding_model: String,
+    pub embedding_size: usize,
+    pub cassette: Option<CassetteConfig>,
+}
+
+/// Record the OpenAI calls or replay them without network, see `cassette`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CassetteConfig {
+    pub mode: CassetteMode,
+    #[serde(default = "default_cassette")]
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CassetteMode {
+    /// Call OpenAI and append every response to the cassette.
+    Record,
+    /// Answer from the cassette only; requests not recorded fail.
+    Replay,
+}
+
+fn default_cassette() -> PathBuf {
+    "./cassette.jsonl".into()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConfig {
+    pub data_dir: PathBuf,
+    pub history_dir: PathBuf,
+    pub embeddings: PathBuf,
+    pub usage_log: PathBuf,
+    /// Accounts of `gpt-rs user` and of OIDC logins.
+    pub users: PathBuf,
+    /// Keys of `gpt-rs api-key`.
+    pub api_keys: PathBuf,
+    /// Requests and tokens used by the API keys, written every few seconds.
+    pub api_key_usage: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BudgetConfig {
+    pub max_tokens: u16,
+    /// Tokens of earlier messages sent with a question, summary included.
+    pub max_history: u16,
+    pub response_size: u16,
+    /// Size of the summary replacing messages which exceed `max_history`.
+    pub summary_size: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatMode {
+    /// Every question is answered with the most similar articles prepended.
+    Context,
+    /// The model calls a search tool when it needs articles.
+    Tools,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatConfig {
+    pub mode: ChatMode,
+    /// Offer the `open_article` tool in addition to `search_knowledge_base`.
+    pub open_article: bool,
+    /// Maximum number of tool-calling rounds before the model must answer.
+    pub max_tool_rounds: usize,
+    /// Default number of articles returned by a search.
+    pub search_results: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// One JSON lines file per conversation in `paths.history_dir`.
+    Jsonl,
+    /// A single SQLite database at `storage.database`.
+    Sqlite,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub database: PathBuf,
+}
+
+/// Limits enforced by the background cleanup task. Unset limits are not
+/// enforced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub enabled: bool,
+    /// Only log what would be removed.
+    pub dry_run: bool,
+    pub interval_minutes: u64,
+    /// Conversations not updated for this long are removed.
+    pub max_age_days: Option<u64>,
+    /// Least recently updated conversations are removed above this total size.
+    pub max_size_mb: Option<u64>,
+    /// Only the most recently updated conversations of each user are kept.
+    pub max_conversations_per_user: Option<usize>,
+    /// Empty conversations and history files without metadata are removed
+    /// once they are this old.
+    pub grace_hours: u64,
+}
+
+/// Token buckets limiting websocket messages and requests to `/api`, `/v1`
+/// and the login pages.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Take the client address from `X-Forwarded-For`, behind a reverse proxy.
+    pub trust_forwarded_for: bool,
+    /// Reverse proxies in front of the server, each appending the address it
+    /// got the request from to `X-Forwarded-For`.
+    pub trusted_proxies: usize,
+    /// Per browser session or API key.
+    pub session: BucketConfig,
+    pub ip: BucketConfig,
+    /// Shared by all clients.
+    pub global: BucketConfig,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BucketConfig {
+    /// Sustained rate; 0 disables the limit.
+    pub per_minute: f64,
+    /// Requests allowed at once after a quiet period.
+    pub burst: u32,
+}
+
+/// Audit trace of every turn, see `trace`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TraceConfig {
+    pub enabled: bool,
+    pub path: PathBuf,
+    /// Regular expressions whose matches are replaced by `[redacted]`.
+    pub redact: Vec<String>,
+    /// The file is rotated before growing beyond this size; 0 never rotates.
+    pub max_size_mb: u64,
+    /// Rotated files kept, `<path>.1` being the newest.
+    pub keep: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Require a login; otherwise every browser session is its own
+    /// anonymous user.
+    pub enabled: bool,
+    /// Usernames of the password accounts which may see `/status` and
+    /// `/admin/usage`; OIDC accounts are never admins.
+    pub admins: Vec<String>,
+    pub oidc: Option<OidcConfig>,
+}
+
+/// Login through an OpenID Connect provider, with the authorization code
+/// flow. The user is identified by the provider's userinfo endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OidcConfig {
+    /// Base URL of `/.well-known/openid-configuration`.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// `/login/oidc/callback` of this server as reachable by browsers.
+    pub redirect_url: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: String,
+    /// Label of the login button.
+    #[serde(default = "default_provider_name")]
+    pub name: String,
+}
+
+fn default_scopes() -> String {
+    "openid profile email".to_string()
+}
+
+fn default_provider_name() -> String {
+    "single sign-on".to_string()
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            trust_forwarded_for: false,
+            trusted_proxies: 1,
+            session: BucketConfig {
+                per_minute: 20.0,
+                burst: 5,
+            },
+            ip: BucketConfig {
+                per_minute: 60.0,
+                burst: 20,
+            },
+            global: BucketConfig {
+                per_minute: 0.0,
+                burst: 50,
+            },
+        }
+    }
+}
+
+impl Default for BucketConfig {
+    fn default() -> Self {
+        Self {
+            per_minute: 60.0,
+            burst: 10,
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: "0.0.0.0:5000".to_string(),
+            session_secret: None,
+            heartbeat_seconds: 30,
+            idle_timeout_seconds: 90,
+        }
+    }
+}
+
+impl Default for OpenAIConfig {
+    fn default() -> Self {
+        Self {
+            api_key: None,
+            chat_model: "gpt-3.5-turbo".to_string(),
+            embedding_model: "text-embedding-ada-002".to_string(),
+            embedding_size: 1536,
+            cassette: None,
+        }
+    }
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        Self {
+            data_dir: "./data".into(),
+            history_dir: "./history".into(),
+            embeddings: "./embeddings.csv".into(),
+            usage_log: "./usage.jsonl".into(),
+            users: "./users.json".into(),
+            api_keys: "./api_keys.json".into(),
+            api_key_usage: "./api_key_usage.json".into(),
+        }
+    }
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            mode: ChatMode::Context,
+            open_article: false,
+            max_tool_rounds: 4,
+            search_results: 3,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Jsonl,
+            database: "./history.db".into(),
+        }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dry_run: false,
+            interval_minutes: 60,
+            max_age_days: None,
+            max_size_mb: None,
+            max_conversations_per_user: None,
+            grace_hours: 24,
+        }
+    }
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "./traces.jsonl".into(),
+            redact: vec![],
+            max_size_mb: 100,
+            keep: 5,
+        }
+    }
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        Self {
+            max_tokens: 4096,
+            max_history: 1024,
+            response_size: 512,
+            summary_size: 256,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from (in increasing priority) built-in defaults,
+    /// the TOML file, `GPT_RS_*` environment variables and `key=value` overrides
+    /// given on the command line.
+    pub fn load(path: Option<&Path>, overrides: &[String]) -> Result<Self> {
+        let mut table = match path {
+            Some(path) => read_table(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                read_table(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => toml::Table::new(),
+        };
+
+        let mut set = set_env_values(&mut table, std::env::vars())?;
+        let from_env = set.len();
+
+        for over in overrides {
+            let (key, value) = over
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Override `{}` must look like section.key=value", over))?;
+            set_value(&mut table, key.trim(), value.trim())
+                .with_context(|| format!("Invalid override `{}`", over))?;
+            set.push((key.trim().to_string(), value.trim().to_string()));
+        }
+
+        let (env, overrides) = set.split_at(from_env);
+        let mut config = deserialize(table, env, overrides).context("Invalid configuration")?;
+
+        if config.openai.api_key.is_none() {
+            config.openai.api_key = std::env::var("OPENAI_API_KEY").ok();
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.server
+            .listen
+            .parse::<std::net::SocketAddr>()
+            .with_context(|| format!("server.listen: `{}` is not a socket address", self.server.listen))?;
+
+        if let Some(secret) = &self.server.session_secret {
+            if secret.len() < 64 {
+                bail!(
+                    "server.session_secret must be at least 64 bytes long, got {}",
+                    secret.len()
+                );
+            }
+        }
+
+        if self.openai.chat_model.is_empty() {
+            bail!("openai.chat_model must not be empty");
+        }
+        if self.openai.embedding_model.is_empty() {
+            bail!("openai.embedding_model must not be empty");
+        }
+        if self.openai.embedding_size == 0 {
+            bail!("openai.embedding_size must be positive");
+        }
+
+        let budget = &self.budget;
+        if u32::from(budget.max_history) + u32::from(budget.response_size)
+            >= u32::from(budget.max_tokens)
+        {
+            bail!(
+                "budget.max_history ({}) + budget.response_size ({}) must be less than budget.max_tokens ({})",
+                budget.max_history,
+                budget.response_size,
+                budget.max_tokens
+            );
+        }
+        if budget.summary_size == 0 {
+            bail!("budget.summary_size must be positive");
+        }
+        if budget.summary_size >= budget.max_history {
+            bail!(
+                "budget.summary_size ({}) must be less than budget.max_history ({})",
+                budget.summary_size,
+                budget.max_history
+            );
+        }
+        if self.chat.search_results == 0 {
+            bail!("chat.search_results must be positive");
+        }
+        if self.server.heartbeat_seconds == 0 {
+            bail!("server.heartbeat_seconds must be positive");
+        }
+        if self.server.idle_timeout_seconds <= self.server.heartbeat_seconds {
+            bail!("server.idle_timeout_seconds must be longer than server.heartbeat_seconds");
+        }
+
+        if self.retention.enabled && self.retention.interval_minutes == 0 {
+            bail!("retention.interval_minutes must be positive");
+        }
+        if self.rate_limit.trust_forwarded_for && self.rate_limit.trusted_proxies == 0 {
+            bail!("rate_limit.trusted_proxies must be positive with trust_forwarded_for");
+        }
+        for (name, bucket) in [
+            ("session", &self.rate_limit.session),
+            ("ip", &self.rate_limit.ip),
+            ("global", &self.rate_limit.global),
+        ] {
+            if bucket.per_minute < 0.0 || !bucket.per_minute.is_finite() {
+                bail!("rate_limit.{}.per_minute must be 0 or positive", name);
+            }
+            if bucket.per_minute > 0.0 && bucket.burst == 0 {
+                bail!("rate_limit.{}.burst must be positive", name);
+            }
+        }
+        if let Some(oidc) = &self.auth.oidc {
+            reqwest::Url::parse(&oidc.issuer)
+                .with_context(|| format!("auth.oidc.issuer: `{}` is not a URL", oidc.issuer))?;
+            reqwest::Url::parse(&oidc.redirect_url).with_context(|| {
+                format!("auth.oidc.redirect_url: `{}` is not a URL", oidc.redirect_url)
+            })?;
+            if oidc.client_id.is_empty() {
+                bail!("auth.oidc.client_id must not be empty");
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks what answering needs: the OpenAI API key and the articles.
+    /// Commands managing accounts, keys or stored conversations run without.
+    pub fn validate_bot(&self) -> Result<()> {
+        let replay = matches!(&self.openai.cassette, Some(c) if c.mode == CassetteMode::Replay);
+        match &self.openai.api_key {
+            Some(key) if !key.is_empty() => {}
+            // Nothing is sent to OpenAI
+            _ if replay => {}
+            _ => bail!("OpenAI API key is not set: use openai.api_key or OPENAI_API_KEY"),
+        }
+
+        if !self.paths.data_dir.is_dir() {
+            bail!("paths.data_dir: {} is not a directory", self.paths.data_dir.display());
+        }
+        if !self.paths.embeddings.is_file() {
+            bail!("paths.embeddings: {} is not a file", self.paths.embeddings.display());
+        }
+        Ok(())
+    }
+
+    pub fn session_secret(&self) -> Vec<u8> {
+        match &self.server.session_secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                use rand::RngCore;
+                let mut secret = vec![0u8; 64];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        }
+    }
+
+    pub fn api_key(&self) -> &str {
+        self.openai.api_key.as_deref().unwrap_or_default()
+    }
+}
+
+fn read_table(path: &Path) -> Result<toml::Table> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Couldn't read config file {}", path.display()))?;
+    toml::from_str(&content).with_context(|| format!("Couldn't parse config file {}", path.display()))
+}
+
+/// Sets the keys of the `GPT_RS_*` variables among `vars`, `__` separating
+/// sections: `GPT_RS_SERVER__LISTEN` sets `server.listen`. Returns the keys
+/// set with their values.
+fn set_env_values(
+    table: &mut toml::Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<Vec<(String, String)>> {
+    let mut set = vec![];
+    for (key, value) in vars {
+        if key == "GPT_RS_CONFIG" {
+            continue;
+        }
+        if let Some(key) = key.strip_prefix(ENV_PREFIX) {
+            let key = key.to_lowercase().replace("__", ".");
+            set_value(table, &key, &value)
+                .with_context(|| format!("Invalid environment variable {}{}", ENV_PREFIX, key))?;
+            set.push((key, value));
+        }
+    }
+    Ok(set)
+}
+
+/// Deserializes the configuration from `table`, in which `env` and
+/// `overrides` were set. A value read as a number, boolean or array for an
+/// option taking a string, e.g. an all-digit API key, is taken as the string
+/// it was given as. Unknown keys set by environment variables are ignored
+/// with a warning, as the environment may hold variables of other versions.
+fn deserialize(
+    mut table: toml::Table,
+    env: &[(String, String)],
+    overrides: &[(String, String)],
+) -> Result<Config> {
+    loop {
+        let error = match serde_path_to_error::deserialize(Value::Table(table.clone())) {
+            Ok(config) => return Ok(config),
+            Err(error) => error,
+        };
+        let path = error.path().to_string();
+        // Overrides come last, so theirs is the value in the table
+        let given = overrides.iter().chain(env).find(|(key, _)| *key == path);
+        if let Some((key, value)) = given {
+            if !lookup(&table, key).is_some_and(Value::is_str) {
+                insert(&mut table, key, Value::String(value.clone()))?;
+                continue;
+            }
+        }
+        if let Some(key) = unknown_key(&path, &error.inner().to_string()) {
+            let within =
+                |(set, _): &(String, String)| *set == key || set.starts_with(&format!("{}.", key));
+            if env.iter().any(within) && !overrides.iter().any(within) {
+                warn!("Ignoring unknown setting `{}` of the environment", key);
+                remove(&mut table, &key);
+                continue;
+            }
+        }
+        return Err(error.into_inner().into());
+    }
+}
+
+/// The key named by an unknown field error at `path`, which is that of the
+/// field or of its section.
+fn unknown_key(path: &str, message: &str) -> Option<String> {
+    let field = message.strip_prefix("unknown field `")?.split('`').next()?;
+    Some(match path {
+        "." => field.to_string(),
+        _ if path.rsplit('.').next() == Some(field) => path.to_string(),
+        _ => format!("{}.{}", path, field),
+    })
+}
+
+/// Sets a dotted `key` in the table. The value is parsed as a TOML value
+/// when possible (numbers, booleans, arrays) and kept as a string otherwise.
+fn set_value(table: &mut toml::Table, key: &str, value: &str) -> Result<()> {
+    let value = toml::from_str::<toml::Table>(&format!("v = {}", value))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| Value::String(value.to_string()));
+    insert(table, key, value)
+}
+
+fn insert(table: &mut toml::Table, key: &str, value: Value) -> Result<()> {
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts.pop().filter(|s| !s.is_empty()).ok_or_else(|| anyhow!("empty key"))?;
+
+    let mut current = table;
+    for part in parts {
+        current = current
+            .entry(part.to_string())
+            .or_insert_with(|| Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("`{}` is not a section", part))?;
+    }
+    current.insert(last.to_string(), value);
+    Ok(())
+}
+
+fn lookup<'t>(table: &'t toml::Table, key: &str) -> Option<&'t Value> {
+    let (section, last) = match key.rsplit_once('.') {
+        Some((section, last)) => (lookup(table, section)?.as_table()?, last),
+        None => (table, key),
+    };
+    section.get(last)
+}
+
+fn remove(table: &mut toml::Table, key: &str) {
+    let section = match key.rsplit_once('.') {
+        Some((section, _)) => lookup_mut(table, section).and_then(Value::as_table_mut),
+        None => Some(table),
+    };
+    if let Some(section) = section {
+        section.remove(key.rsplit('.').next().unwrap_or(key));
+    }
+}
+
+fn lookup_mut<'t>(table: &'t mut toml::Table, key: &str) -> Option<&'t mut Value> {
+    let (section, last) = match key.rsplit_once('.') {
+        Some((section, last)) => (lookup_mut(table, section)?.as_table_mut()?, last),
+        None => (table, key),
+    };
+    section.get_mut(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(table: toml::Table) -> Result<Config> {
+        Ok(Value::Table(table).try_into()?)
+    }
+
+    /// A configuration passing `validate` and `validate_bot` with its files in
+    /// `dir`.
+    fn valid_config(dir: &Path) -> Config {
+        let mut config = Config::default();
+        config.openai.api_key = Some("key".to_string());
+        config.paths.data_dir = dir.to_path_buf();
+        config.paths.embeddings = dir.join("embeddings.csv");
+        config.paths.history_dir = dir.join("history");
+        std::fs::write(&config.paths.embeddings, "").unwrap();
+        config.validate().unwrap();
+        config.validate_bot().unwrap();
+        config
+    }
+
+    #[test]
+    fn set_value_parses_toml_values() {
+        let mut table = toml::Table::new();
+        set_value(&mut table, "server.listen", "127.0.0.1:8080").unwrap();
+        set_value(&mut table, "rate_limit.enabled", "false").unwrap();
+        set_value(&mut table, "chat.search_results", "7").unwrap();
+        set_value(&mut table, "auth.admins", r#"["alice", "bob"]"#).unwrap();
+
+        let config = config(table).unwrap();
+        assert_eq!(config.server.listen, "127.0.0.1:8080");
+        assert!(!config.rate_limit.enabled);
+        assert_eq!(config.chat.search_results, 7);
+        assert_eq!(config.auth.admins, ["alice", "bob"]);
+    }
+
+    #[test]
+    fn set_value_rejects_bad_keys() {
+        let mut table = toml::Table::new();
+        set_value(&mut table, "server.listen", "x").unwrap();
+        assert!(set_value(&mut table, "server.listen.port", "1").is_err());
+        assert!(set_value(&mut table, "server.", "1").is_err());
+
+        set_value(&mut table, "server.unknown", "1").unwrap();
+        assert!(config(table).is_err());
+    }
+
+    #[test]
+    fn env_values_set_dotted_keys() {
+        let vars = [
+            ("GPT_RS_SERVER__LISTEN", "0.0.0.0:80"),
+            ("GPT_RS_OPENAI__CHAT_MODEL", "gpt-4"),
+            ("GPT_RS_CONFIG", "ignored.toml"),
+            ("OTHER__VAR", "1"),
+        ]
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        let mut table = toml::Table::new();
+        set_env_values(&mut table, vars.into_iter()).unwrap();
+        assert_eq!(table.len(), 2);
+
+        let config = config(table).unwrap();
+        assert_eq!(config.server.listen, "0.0.0.0:80");
+        assert_eq!(config.openai.chat_model, "gpt-4");
+    }
+
+    fn load(env: &[(&str, &str)], overrides: &[(&str, &str)]) -> Result<Config> {
+        let vars = env.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+        let mut table = toml::Table::new();
+        let env = set_env_values(&mut table, vars)?;
+        let overrides: Vec<_> = overrides
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        for (key, value) in &overrides {
+            set_value(&mut table, key, value)?;
+        }
+        deserialize(table, &env, &overrides)
+    }
+
+    #[test]
+    fn string_options_keep_numbers_as_given() {
+        let digits = "0".repeat(64);
+        let config = load(
+            &[("GPT_RS_OPENAI__API_KEY", "12345")],
+            &[
+                ("server.session_secret", &digits),
+                ("server.listen", "true"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.openai.api_key.as_deref(), Some("12345"));
+        assert_eq!(
+            config.server.session_secret.as_deref(),
+            Some(digits.as_str())
+        );
+        assert_eq!(config.server.listen, "true");
+
+        // Overrides win over the environment
+        let config = load(
+            &[("GPT_RS_OPENAI__API_KEY", "12345")],
+            &[("openai.api_key", "678")],
+        )
+        .unwrap();
+        assert_eq!(config.openai.api_key.as_deref(), Some("678"));
+
+        assert!(load(&[], &[("chat.search_results", "many")]).is_err());
+    }
+
+    #[test]
+    fn unknown_environment_keys_are_ignored() {
+        let env = [
+            ("GPT_RS_SERVER__UNKNOWN", "1"),
+            ("GPT_RS_NOSECTION__KEY", "x"),
+            ("GPT_RS_OPENAI__CHAT_MODEL", "gpt-4"),
+        ];
+        let config = load(&env, &[]).unwrap();
+        assert_eq!(config.openai.chat_model, "gpt-4");
+
+        assert!(load(&[], &[("server.unknown", "1")]).is_err());
+        assert!(load(&env, &[("server.unknown", "1")]).is_err());
+    }
+
+    #[test]
+    fn validate_checks_summary_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = valid_config(dir.path());
+        config.budget.summary_size = 0;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("budget.summary_size"), "{}", error);
+    }
+
+    #[test]
+    fn validate_bot_checks_the_key_and_the_articles() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = valid_config(dir.path());
+        config.openai.api_key = None;
+        config.validate().unwrap();
+        let error = config.validate_bot().unwrap_err().to_string();
+        assert!(error.contains("OpenAI API key"), "{}", error);
+
+        let mut config = valid_config(dir.path());
+        config.paths.embeddings = dir.path().join("missing.csv");
+        config.validate().unwrap();
+        let error = config.validate_bot().unwrap_err().to_string();
+        assert!(error.contains("paths.embeddings"), "{}", error);
+    }
+
+    #[test]
+    fn validate_checks_rate_limit_hops() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = valid_config(dir.path());
+        config.rate_limit.trust_forwarded_for = true;
+        config.rate_limit.trusted_proxies = 0;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("rate_limit.trusted_proxies"), "{}", error);
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::history::History;
+use crate::session::random_id;
+use crate::store::HistoryStore;
+
+const TITLE_LENGTH: usize = 40;
+
+/// Metadata of a conversation, kept by the `HistoryStore` next to its messages.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conversation {
+    pub id: String,
+    /// Empty for conversations imported from sessions which never claimed them.
+    pub owner: String,
+    pub title: String,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+}
+
+pub struct Conversations {
+    store: Arc<dyn HistoryStore>,
+}
+
+/// Ids end up in file names, so only alphanumeric ones are accepted.
+pub fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+impl Conversations {
+    pub fn new(store: Arc<dyn HistoryStore>) -> Self {
+        Self { store }
+    }
+
+    pub fn create(&self, owner: &str) -> Result<Conversation> {
+        self.adopt(&random_id(), owner)
+    }
+
+    /// Gives `owner` the conversation `id`, creating its metadata if needed.
+    pub fn adopt(&self, id: &str, owner: &str) -> Result<Conversation> {
+        let now = Utc::now();
+        let mut conversation = self.get(id)?.unwrap_or_else(|| Conversation {
+            id: id.to_string(),
+            owner: String::new(),
+            title: String::new(),
+            created: now,
+            updated: now,
+        });
+        conversation.owner = owner.to_string();
+        self.store.save_conversation(&conversation)?;
+        Ok(conversation)
+    }
+
+    /// Whether a session may adopt `id`: its history was stored before
+    /// conversations existed and nobody owns it yet.
+    pub fn claimable(&self, id: &str) -> Result<bool> {
+        if !valid_id(id) {
+            return Ok(false);
+        }
+        Ok(match self.get(id)? {
+            Some(conversation) => conversation.owner.is_empty(),
+            None => self.store.history_exists(id)?,
+        })
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<Conversation>> {
+        if !valid_id(id) {
+            return Ok(None);
+        }
+        self.store.conversation(id)
+    }
+
+    /// Returns the conversation if it belongs to `owner`.
+    pub fn get_owned(&self, id: &str, owner: &str) -> Result<Conversation> {
+        match self.get(id)? {
+            Some(conversation) if conversation.owner == owner => Ok(conversation),
+            _ => bail!("No conversation {}", id),
+        }
+    }
+
+    /// Conversations of `owner`, most recently updated first.
+    pub fn list(&self, owner: &str) -> Result<Vec<Conversation>> {
+        let mut conversations = self.store.conversations(owner)?;
+        conversations.sort_by_key(|c| std::cmp::Reverse(c.updated));
+        Ok(conversations)
+    }
+
+    /// Gives every conversation of `from` to `to`.
+    pub fn transfer(&self, from: &str, to: &str) -> Result<usize> {
+        let conversations = self.store.conversations(from)?;
+        for mut conversation in conversations.iter().cloned() {
+            conversation.owner = to.to_string();
+            self.store.save_conversation(&conversation)?;
+        }
+        Ok(conversations.len())
+    }
+
+    pub fn rename(&self, id: &str, owner: &str, title: &str) -> Result<Conversation> {
+        let mut conversation = self.get_owned(id, owner)?;
+        conversation.title = title.trim().to_string();
+        conversation.updated = Utc::now();
+        self.store.save_conversation(&conversation)?;
+        Ok(conversation)
+    }
+
+    pub fn delete(&self, id: &str, owner: &str) -> Result<()> {
+        let conversation = self.get_owned(id, owner)?;
+        self.store.delete_conversation(&conversation.id)
+    }
+
+    /// Marks the conversation as updated by a new message; the first user
+    /// message becomes the title of an untitled conversation.
+    pub fn touch(&self, id: &str, message: &str) -> Result<()> {
+        if let Some(mut conversation) = self.get(id)? {
+            if conversation.title.is_empty() {
+                conversation.title = title_from(message);
+            }
+            conversation.updated = Utc::now();
+            self.store.save_conversation(&conversation)?;
+        }
+        Ok(())
+    }
+
+    pub fn history<'a>(&self, conversation: &Conversation) -> Result<History<'a>> {
+        History::load(self.store.clone(), &conversation.id)
+    }
+}
+
+pub(crate) fn title_from(message: &str) -> String {
+    let message = message.trim();
+    match message.char_indices().nth(TITLE_LENGTH) {
+        Some((idx, _)) => format!("{}…", &message[..idx]),
+        None => message.to_string(),
+    }
+}
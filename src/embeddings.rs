@@ -1,22 +1,21 @@
-use crate::{DATA_DIR, EMBEDDING_SIZE};
 use anyhow::Error;
 use async_openai::types::{ChatCompletionRequestMessage, Role};
 use ndarray::{Array, Array1, Array2, ArrayView1, Axis};
 use serde::{Deserialize, Serialize};
 use std::{
     borrow::Cow,
+    fmt,
     fs::File,
     io::{BufReader, Read},
-    path::Path,
+    path::{Path, PathBuf},
 };
 use crate::timer;
-//use tracing::info;
-use std::println as info;
 
 #[derive(Debug)]
 pub struct Embeddings {
     filenames: Vec<String>,
     embeddings: Array2<f32>,
+    data_dir: PathBuf,
 }
 
 #[derive(Debug, Deserialize)]
@@ -26,17 +25,22 @@ pub struct Article {
     pub tokens: usize,
 }
 
-impl ToString for Article {
-    fn to_string(&self) -> String {
-        format!(r#"\n\n Article {}:\n"""\n{}\n""""#, self.title, self.body)
+impl fmt::Display for Article {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, r#"\n\n Article {}:\n"""\n{}\n""""#, self.title, self.body)
     }
 }
 
 impl Article {
-    fn estimated_total_tokens(&self) -> usize {
+    /// Article text as it appears in the context, numbered for citations.
+    pub fn numbered(&self, n: usize) -> String {
+        format!(r#"\n\n Article [{}] {}:\n"""\n{}\n""""#, n, self.title, self.body)
+    }
+
+    pub fn estimated_total_tokens(&self) -> usize {
         // Approximately how many tokens, according to the embedding model, are in the text returned from to_string() method.
         // Determined empirically.
-        return self.tokens + 15;
+        self.tokens + 15
     }
 }
 
@@ -55,9 +59,20 @@ impl<'a> Filename<'a> {
     }
 }
 
+impl Filename<'_> {
+    /// The score to rank by. A NaN score, e.g. of a zero vector, ranks last.
+    fn rank(&self) -> f32 {
+        if self.score.is_nan() {
+            f32::NEG_INFINITY
+        } else {
+            self.score
+        }
+    }
+}
+
 impl PartialEq for Filename<'_> {
     fn eq(&self, other: &Self) -> bool {
-        self.score == other.score
+        self.cmp(other) == std::cmp::Ordering::Equal
     }
 }
 
@@ -65,16 +80,29 @@ impl Eq for Filename<'_> {}
 
 impl PartialOrd for Filename<'_> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        other.score.partial_cmp(&self.score)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for Filename<'_> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        other.score.partial_cmp(&self.score).unwrap()
+        other.rank().total_cmp(&self.rank())
     }
 }
 
+const CONTEXT_PROMPT: &str = "Use the below articles about the game Vallheim to answer the subsequent question. Cite the articles you used by their number, like [1]. If the answer cannot be found in the articles, write 'I could not find an answer.'";
+const CONTEXT_PROMPT_TOKENS: usize = 55; // number of tokens in CONTEXT_PROMPT; re-calculate this if you change the phrase
+
+/// An article considered for the context.
+#[derive(Debug)]
+pub struct Candidate<'a> {
+    pub filename: Filename<'a>,
+    pub article: Article,
+    pub tokens: usize,
+    /// Whether the article fits into the token budget.
+    pub fits: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContextInfo<'a> {
     pub filenames: Vec<Filename<'a>>,
@@ -83,7 +111,11 @@ pub struct ContextInfo<'a> {
 
 impl Embeddings {
     #[tracing::instrument]
-    pub fn load<R: Read + std::fmt::Debug>(reader: R) -> Result<Self, Error> {
+    pub fn load<R: Read + std::fmt::Debug>(
+        reader: R,
+        embedding_size: usize,
+        data_dir: PathBuf,
+    ) -> Result<Self, Error> {
         let mut rdr = csv::Reader::from_reader(reader);
         let mut record = csv::ByteRecord::new();
 
@@ -95,15 +127,39 @@ impl Embeddings {
             let vec: Vec<f32> = serde_json::from_slice(&record[2]).unwrap();
             embeddings.extend_from_slice(&vec);
         }
-        let len = embeddings.len() / EMBEDDING_SIZE;
-        let embeddings = Array::from_shape_vec((len, EMBEDDING_SIZE), embeddings)?;
+        let len = embeddings.len() / embedding_size;
+        let embeddings = Array::from_shape_vec((len, embedding_size), embeddings)?;
         Ok(Embeddings {
             filenames,
             embeddings,
+            data_dir,
         })
     }
 
-    pub fn embedding(&self, index: usize) -> ArrayView1<f32> {
+    /// Number of indexed articles.
+    pub fn count(&self) -> usize {
+        self.filenames.len()
+    }
+
+    pub fn dimensions(&self) -> usize {
+        self.embeddings.ncols()
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// Returns the indexed filename equal to `filename`, if any.
+    pub fn filename(&self, filename: &str) -> Option<&str> {
+        self.filenames.iter().find(|f| *f == filename).map(String::as_str)
+    }
+
+    pub fn article(&self, filename: &str) -> Result<Article, Error> {
+        let file = File::open(self.data_dir.join(filename))?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    pub fn embedding(&self, index: usize) -> ArrayView1<'_, f32> {
         self.embeddings.index_axis(Axis(0), index)
     }
 
@@ -118,46 +174,164 @@ impl Embeddings {
         top
     }
 
-    pub fn prepare_context(
+    /// Walks the articles in order of similarity and decides which of them fit
+    /// into `token_budget` together with the context prompt. Returns at least
+    /// the `k` most similar articles and all articles fitting into the context.
+    pub fn candidates(
+        &self,
+        emb: &Array1<f32>,
+        token_budget: u16,
+        k: usize,
+    ) -> Result<Vec<Candidate<'_>>, Error> {
+        self.walk(emb, token_budget, |idx, fitting| idx >= k && !fitting)
+    }
+
+    /// The `k` most similar articles, marked as in `candidates`.
+    pub fn search(
+        &self,
+        emb: &Array1<f32>,
+        token_budget: u16,
+        k: usize,
+    ) -> Result<Vec<Candidate<'_>>, Error> {
+        self.walk(emb, token_budget, |idx, _| idx >= k)
+    }
+
+    /// Candidates in order of similarity until `done(idx, fitting)`, where
+    /// `fitting` tells whether every article so far fits.
+    fn walk(
         &self,
         emb: &Array1<f32>,
         token_budget: u16,
-    ) -> Result<(ChatCompletionRequestMessage, ContextInfo), Error> {
+        done: impl Fn(usize, bool) -> bool,
+    ) -> Result<Vec<Candidate<'_>>, Error> {
         let similar = timer!("top_similar", {
             self.top_similar(emb)
         });
 
-        let mut message = ChatCompletionRequestMessage {
-            role: Role::User,
-            content: "Use the below articles about the game Vallheim to answer the subsequent question. If the answer cannot be found in the articles, write 'I could not find an answer.'".to_string(),
-            name: None,
-        };
-        let mut total_tokens = 41; // number of tokens in the initial content phrase above; re-calculate this if you change the phrase
-
-        let dir = Path::new(DATA_DIR);
+        let mut total_tokens = CONTEXT_PROMPT_TOKENS;
+        let mut fitting = true;
+        let mut candidates = vec![];
 
-        let mut filenames = vec![];
-        let mut size = 0;
-
-        for filename in similar {
-            let article: Article = serde_json::from_reader(BufReader::new(File::open(
-                dir.join(filename.filename.as_ref()),
-            )?))?;
-            let mut new_message = message.clone();
-            new_message.content.push_str(&article.to_string());
-
-            total_tokens += article.estimated_total_tokens();
-            // ^^^ can be calculated precisely by calling  
+        for (idx, filename) in similar.into_iter().enumerate() {
+            if done(idx, fitting) {
+                break;
+            }
+            let article = self.article(&filename.filename)?;
+            let tokens = article.estimated_total_tokens();
+            // ^^^ can be calculated precisely by calling
             // tiktoken_rs::async_openai::num_tokens_from_messages(CHAT_MODEL, &[new_message.clone()]) but it is expensive
 
-            if total_tokens < (token_budget as usize) {
-                message = new_message;
-                filenames.push(filename);
-                size += total_tokens;
+            let fits = fitting && total_tokens + tokens < (token_budget as usize);
+            if fits {
+                total_tokens += tokens;
             } else {
-                break;
+                fitting = false;
             }
+            candidates.push(Candidate {
+                filename,
+                article,
+                tokens,
+                fits,
+            });
         }
-        Ok((message, ContextInfo { filenames, size }))
+        Ok(candidates)
+    }
+
+    pub fn prepare_context(
+        &self,
+        emb: &Array1<f32>,
+        token_budget: u16,
+    ) -> Result<(ChatCompletionRequestMessage, ContextInfo<'_>), Error> {
+        Ok(context_message(self.candidates(emb, token_budget, 0)?))
+    }
+}
+
+/// The context message made of the `candidates` up to the first one not
+/// fitting into the token budget.
+pub fn context_message(
+    candidates: Vec<Candidate<'_>>,
+) -> (ChatCompletionRequestMessage, ContextInfo<'_>) {
+    let mut message = ChatCompletionRequestMessage {
+        role: Role::User,
+        content: CONTEXT_PROMPT.to_string(),
+        name: None,
+    };
+
+    let mut filenames = vec![];
+    let mut size = CONTEXT_PROMPT_TOKENS;
+
+    for candidate in candidates {
+        if !candidate.fits {
+            break;
+        }
+        message
+            .content
+            .push_str(&candidate.article.numbered(filenames.len() + 1));
+        filenames.push(candidate.filename);
+        size += candidate.tokens;
+    }
+    (message, ContextInfo { filenames, size })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filenames_rank_by_score_with_nan_last() {
+        let mut filenames = [
+            Filename::new("nan", f32::NAN),
+            Filename::new("low", 0.1),
+            Filename::new("negative nan", -f32::NAN),
+            Filename::new("high", 0.9),
+        ];
+        filenames.sort_unstable();
+        let order: Vec<&str> = filenames.iter().map(|f| &*f.filename).collect();
+        assert_eq!(order[..2], ["high", "low"]);
+    }
+
+    /// Three articles of 115 tokens in order of similarity to `[1, 0, 0]`.
+    fn embeddings(dir: &Path) -> Embeddings {
+        let mut csv = String::from(",filename,embedding\n");
+        for (idx, (name, vector)) in [("a", "[1,0,0]"), ("b", "[0.9,0.1,0]"), ("c", "[0,1,0]")]
+            .into_iter()
+            .enumerate()
+        {
+            csv.push_str(&format!("{},{}.json,\"{}\"\n", idx, name, vector));
+            let article = r#"{"title": "T", "body": "B", "tokens": 100}"#;
+            std::fs::write(dir.join(format!("{}.json", name)), article).unwrap();
+        }
+        Embeddings::load(csv.as_bytes(), 3, dir.to_path_buf()).unwrap()
+    }
+
+    fn names(candidates: &[Candidate]) -> Vec<(String, bool)> {
+        candidates
+            .iter()
+            .map(|c| (c.filename.filename.to_string(), c.fits))
+            .collect()
+    }
+
+    #[test]
+    fn search_returns_at_most_k_articles() {
+        let dir = tempfile::tempdir().unwrap();
+        let embeddings = embeddings(dir.path());
+        let emb = Array1::from_vec(vec![1.0, 0.0, 0.0]);
+        let found = embeddings.search(&emb, 1000, 1).unwrap();
+        assert_eq!(names(&found), [("a.json".to_string(), true)]);
+        assert_eq!(embeddings.search(&emb, 200, 2).unwrap().len(), 2);
+        assert!(embeddings.search(&emb, 1000, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn candidates_cover_k_and_the_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let embeddings = embeddings(dir.path());
+        let emb = Array1::from_vec(vec![1.0, 0.0, 0.0]);
+        assert_eq!(embeddings.candidates(&emb, 1000, 1).unwrap().len(), 3);
+        assert_eq!(
+            names(&embeddings.candidates(&emb, 200, 0).unwrap()),
+            [("a.json".to_string(), true), ("b.json".to_string(), false)]
+        );
+        assert_eq!(embeddings.candidates(&emb, 200, 3).unwrap().len(), 3);
     }
 }
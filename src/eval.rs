@@ -0,0 +1,233 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::bot::Bot;
+use crate::history::{History, Message};
+use crate::usage::Usage;
+
+/// One line of the evaluation dataset.
+#[derive(Debug, Deserialize)]
+pub struct Case {
+    #[serde(default)]
+    pub id: Option<String>,
+    pub question: String,
+    /// Filenames of the articles which answer the question.
+    pub expected: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub summary: Summary,
+    pub questions: Vec<CaseResult>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub questions: usize,
+    pub errors: usize,
+    /// Mean fraction of expected articles among the top k, by k.
+    pub recall: BTreeMap<usize, f64>,
+    pub mrr: f64,
+    /// Mean fraction of expected articles which fit into the context.
+    pub context_recall: f64,
+    /// Share of answers citing at least one expected article, with `--answer`.
+    pub cited_expected: Option<f64>,
+    pub usage: Usage,
+    pub cost: f64,
+    /// Questions with no expected article among the top k for the largest k.
+    pub failures: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CaseResult {
+    pub id: String,
+    pub question: String,
+    pub expected: Vec<String>,
+    /// Top articles for the largest k, with scores.
+    pub retrieved: Vec<(String, f32)>,
+    /// Rank (from 1) of the first expected article, if retrieved.
+    pub rank: Option<usize>,
+    pub recall: BTreeMap<usize, f64>,
+    pub context: Vec<String>,
+    pub context_recall: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub answer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cited: Option<Vec<String>>,
+    pub usage: Usage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub fn load_cases(path: &Path) -> Result<Vec<Case>> {
+    let file = File::open(path).with_context(|| format!("Couldn't open {}", path.display()))?;
+    let mut cases = vec![];
+    for (idx, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let case: Case = serde_json::from_str(&line)
+            .with_context(|| format!("{}:{}: invalid case", path.display(), idx + 1))?;
+        cases.push(case);
+    }
+    Ok(cases)
+}
+
+/// Runs retrieval, and the full answering flow with `answer`, for every case.
+pub async fn evaluate(bot: &Bot, cases: &[Case], ks: &[usize], answer: bool) -> Report {
+    // Answers are recorded in the usage ledger under this session, apart
+    // from those of users
+    let run = format!("eval:{}", Utc::now().format("%Y%m%dT%H%M%SZ"));
+    let mut results = vec![];
+    for (idx, case) in cases.iter().enumerate() {
+        let id = case.id.clone().unwrap_or_else(|| (idx + 1).to_string());
+        info!("eval {}/{}: {}", idx + 1, cases.len(), case.question);
+        let mut result = CaseResult {
+            id,
+            question: case.question.clone(),
+            expected: case.expected.clone(),
+            ..Default::default()
+        };
+        if let Err(e) = evaluate_case(bot, case, ks, answer, &run, &mut result).await {
+            result.error = Some(format!("{:#}", e));
+        }
+        results.push(result);
+    }
+    Report {
+        summary: summarize(bot, &results, ks, answer),
+        questions: results,
+    }
+}
+
+async fn evaluate_case(
+    bot: &Bot,
+    case: &Case,
+    ks: &[usize],
+    answer: bool,
+    run: &str,
+    result: &mut CaseResult,
+) -> Result<()> {
+    let max_k = ks.iter().copied().max().unwrap_or(1);
+    let (emb, usage) = bot.client.get_embedding(&case.question).await?;
+    result.usage += usage;
+
+    let similar = bot.embeddings.top_similar(&emb);
+    let is_expected = |filename: &str| case.expected.iter().any(|e| e == filename);
+
+    result.rank = similar
+        .iter()
+        .position(|f| is_expected(&f.filename))
+        .map(|idx| idx + 1);
+    for &k in ks {
+        let found = similar
+            .iter()
+            .take(k)
+            .filter(|f| is_expected(&f.filename))
+            .count();
+        result.recall.insert(k, ratio(found, case.expected.len()));
+    }
+    result.retrieved = similar
+        .iter()
+        .take(max_k)
+        .map(|f| (f.filename.to_string(), f.score))
+        .collect();
+
+    let budget = &bot.config.budget;
+    let (_, context_info) = bot
+        .embeddings
+        .prepare_context(&emb, budget.max_tokens.saturating_sub(budget.response_size))?;
+    result.context = context_info
+        .filenames
+        .iter()
+        .map(|f| f.filename.to_string())
+        .collect();
+    let found = result.context.iter().filter(|f| is_expected(f)).count();
+    result.context_recall = ratio(found, case.expected.len());
+
+    if answer {
+        let mut history = History::in_memory();
+        history.name = Some(run.to_string());
+        history.user(Message::user(&case.question)?);
+        let resp = bot.answer(&history).await?;
+        if let Some(info) = &resp.info {
+            result.usage += info.usage;
+            result.cited = Some(
+                info.citations
+                    .cited
+                    .iter()
+                    .map(|n| info.context_info.filenames[n - 1].filename.to_string())
+                    .collect(),
+            );
+        }
+        result.answer = Some(resp.content().to_string());
+    }
+    Ok(())
+}
+
+fn summarize(bot: &Bot, results: &[CaseResult], ks: &[usize], answer: bool) -> Summary {
+    let mut summary = Summary {
+        questions: results.len(),
+        ..Default::default()
+    };
+    let ok: Vec<&CaseResult> = results.iter().filter(|r| r.error.is_none()).collect();
+    summary.errors = results.len() - ok.len();
+    let count = ok.len().max(1) as f64;
+
+    for &k in ks {
+        let sum: f64 = ok.iter().map(|r| r.recall[&k]).sum();
+        summary.recall.insert(k, sum / count);
+    }
+    summary.mrr = ok
+        .iter()
+        .map(|r| r.rank.map(|rank| 1.0 / rank as f64).unwrap_or(0.0))
+        .sum::<f64>()
+        / count;
+    summary.context_recall = ok.iter().map(|r| r.context_recall).sum::<f64>() / count;
+    if answer {
+        let cited = ok
+            .iter()
+            .filter(|r| {
+                r.cited
+                    .iter()
+                    .flatten()
+                    .any(|f| r.expected.contains(f))
+            })
+            .count();
+        summary.cited_expected = Some(cited as f64 / count);
+    }
+
+    for r in results {
+        summary.usage += r.usage;
+    }
+    summary.cost = bot.config.prices.cost(
+        &summary.usage,
+        bot.client.chat_model(),
+        bot.client.embedding_model(),
+    );
+
+    let max_k = ks.iter().copied().max().unwrap_or(1);
+    summary.failures = results
+        .iter()
+        .filter(|r| r.error.is_some() || r.rank.is_none_or(|rank| rank > max_k))
+        .map(|r| r.id.clone())
+        .collect();
+    summary
+}
+
+fn ratio(found: usize, expected: usize) -> f64 {
+    if expected == 0 {
+        1.0
+    } else {
+        found as f64 / expected as f64
+    }
+}
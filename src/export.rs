@@ -0,0 +1,341 @@
+//! Rendering of a conversation to Markdown, JSON or a standalone HTML page.
+//! Only the active branch of the conversation is exported.
+use std::{fmt::Write, str::FromStr};
+
+use anyhow::{bail, Result};
+use askama::Template;
+use async_openai::types::Role;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::citations::{self, Source};
+use crate::conversations::Conversation;
+use crate::history::{History, Message};
+use crate::html::{self, ExportMessage, ExportTemplate, Message as HTMLMsg};
+use crate::usage::Usage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Markdown,
+    Json,
+    Html,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "md" | "markdown" => Format::Markdown,
+            "json" => Format::Json,
+            "html" => Format::Html,
+            _ => bail!("Unknown export format {}, use markdown, json or html", s),
+        })
+    }
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Markdown => "md",
+            Format::Json => "json",
+            Format::Html => "html",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Markdown => "text/markdown; charset=utf-8",
+            Format::Json => "application/json",
+            Format::Html => "text/html; charset=utf-8",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Export {
+    pub conversation: Conversation,
+    pub messages: Vec<ExportedMessage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedMessage {
+    pub id: String,
+    pub parent: Option<String>,
+    pub role: &'static str,
+    pub content: String,
+    pub created: Option<DateTime<Utc>>,
+    /// Articles of the context of an answer; `cited` marks the ones it cites.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<Source>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// The answer was stopped by the user.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub cancelled: bool,
+}
+
+impl ExportedMessage {
+    fn new(message: &Message, base: Option<&str>) -> Self {
+        Self {
+            id: message.id.clone(),
+            parent: message.parent.clone(),
+            role: message.class(),
+            content: message.content().to_string(),
+            created: message.created,
+            sources: sources(message, base),
+            usage: message.info.as_ref().map(|info| info.usage),
+            cancelled: message.cancelled,
+        }
+    }
+}
+
+/// Exports the active branch of `history`. Articles are linked under `base`,
+/// the URL of the server, so that the links work in downloaded files, and
+/// aren't linked without it.
+pub fn export(
+    conversation: &Conversation,
+    history: &History,
+    format: Format,
+    base: Option<&str>,
+) -> Result<String> {
+    match format {
+        Format::Markdown => Ok(markdown(conversation, history, base)),
+        Format::Json => {
+            let export = Export {
+                conversation: conversation.clone(),
+                messages: history
+                    .messages()
+                    .iter()
+                    .map(|m| ExportedMessage::new(m, base))
+                    .collect(),
+            };
+            Ok(serde_json::to_string_pretty(&export)?)
+        }
+        Format::Html => {
+            let template = ExportTemplate {
+                title: title(conversation),
+                conversation,
+                messages: history
+                    .messages()
+                    .iter()
+                    .map(|m| {
+                        let mut message = HTMLMsg::from(m);
+                        if m.msg.role == Role::Assistant {
+                            message.html = html::render_content(m, base);
+                        }
+                        ExportMessage {
+                            created: m.created.map(timestamp).unwrap_or_default(),
+                            sources: cited(m, base),
+                            message,
+                        }
+                    })
+                    .collect(),
+            };
+            Ok(template.render()?)
+        }
+    }
+}
+
+fn markdown(conversation: &Conversation, history: &History, base: Option<&str>) -> String {
+    let mut md = String::new();
+    let _ = writeln!(md, "# {}\n", title(conversation));
+    let _ = writeln!(
+        md,
+        "_Conversation {}, started {}_\n",
+        conversation.id,
+        timestamp(conversation.created)
+    );
+    for message in history.messages() {
+        let speaker = match message.class() {
+            "user" => "You",
+            "assistant" => "AI",
+            _ => "System",
+        };
+        match message.created {
+            Some(created) => {
+                let _ = writeln!(md, "**{}** ({}):\n", speaker, timestamp(created));
+            }
+            None => {
+                let _ = writeln!(md, "**{}**:\n", speaker);
+            }
+        }
+        let _ = writeln!(md, "{}\n", message.content());
+        if message.cancelled {
+            md.push_str("_Stopped before the answer was complete._\n\n");
+        }
+        let sources = cited(message, base);
+        if !sources.is_empty() {
+            md.push_str("Sources:\n\n");
+            for source in sources {
+                if source.url.is_empty() {
+                    let _ = writeln!(md, "- [{}] {}", source.index, source.filename);
+                } else {
+                    let _ = writeln!(
+                        md,
+                        "- [{}] [{}]({})",
+                        source.index, source.filename, source.url
+                    );
+                }
+            }
+            md.push('\n');
+        }
+    }
+    md
+}
+
+/// The sources of `message` with their URLs under `base`, empty without it.
+fn sources(message: &Message, base: Option<&str>) -> Vec<Source> {
+    let Some(info) = &message.info else {
+        return vec![];
+    };
+    citations::sources(info)
+        .into_iter()
+        .map(|mut source| {
+            source.url = base
+                .map(|base| format!("{}{}", base, source.url))
+                .unwrap_or_default();
+            source
+        })
+        .collect()
+}
+
+fn cited(message: &Message, base: Option<&str>) -> Vec<Source> {
+    sources(message, base)
+        .into_iter()
+        .filter(|s| s.cited)
+        .collect()
+}
+
+fn title(conversation: &Conversation) -> String {
+    if conversation.title.is_empty() {
+        "Conversation".to_string()
+    } else {
+        conversation.title.clone()
+    }
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn conversation() -> Conversation {
+        let created = Utc.with_ymd_and_hms(2023, 5, 1, 10, 0, 0).unwrap();
+        Conversation {
+            id: "c1".to_string(),
+            owner: "alice".to_string(),
+            title: String::new(),
+            created,
+            updated: created,
+        }
+    }
+
+    /// A question and an answer citing the first of two articles.
+    fn history() -> History<'static> {
+        let info = serde_json::json!({
+            "context_info": {
+                "filenames": [
+                    { "filename": "rust.json", "score": 0.9 },
+                    { "filename": "go.json", "score": 0.5 },
+                ],
+                "size": 0,
+            },
+            "user_message_tokens": 0,
+            "history_count": 0,
+            "history_size": 0,
+            "usage": { "prompt_tokens": 100, "completion_tokens": 20, "embedding_tokens": 5 },
+            "citations": { "cited": [1], "invalid": [] },
+        });
+        let mut history = History::in_memory();
+        history.push(Message {
+            created: None,
+            ..Message::new(Role::User, "What is Rust?")
+        });
+        history.push(Message {
+            info: Some(serde_json::from_value(info).unwrap()),
+            created: Some(Utc.with_ymd_and_hms(2023, 5, 1, 10, 1, 0).unwrap()),
+            cancelled: true,
+            ..Message::new(Role::Assistant, "A language [1].")
+        });
+        history
+    }
+
+    #[test]
+    fn formats_by_name_or_extension() {
+        assert_eq!("md".parse::<Format>().unwrap(), Format::Markdown);
+        assert_eq!("markdown".parse::<Format>().unwrap(), Format::Markdown);
+        assert_eq!("json".parse::<Format>().unwrap(), Format::Json);
+        assert_eq!("html".parse::<Format>().unwrap().extension(), "html");
+        assert!("pdf".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn markdown_lists_the_cited_sources() {
+        let md = export(&conversation(), &history(), Format::Markdown, None).unwrap();
+        assert_eq!(
+            md,
+            "# Conversation\n\n\
+             _Conversation c1, started 2023-05-01 10:00 UTC_\n\n\
+             **You**:\n\nWhat is Rust?\n\n\
+             **AI** (2023-05-01 10:01 UTC):\n\nA language [1].\n\n\
+             _Stopped before the answer was complete._\n\n\
+             Sources:\n\n- [1] rust.json\n\n"
+        );
+
+        let md = export(
+            &conversation(),
+            &history(),
+            Format::Markdown,
+            Some("https://x.org"),
+        )
+        .unwrap();
+        assert!(md.contains("- [1] [rust.json](https://x.org/context/rust.json)\n"));
+        assert!(!md.contains("go.json"));
+    }
+
+    #[test]
+    fn json_exports_the_active_branch() {
+        let mut history = history();
+        let question = history.messages()[0].id.clone();
+        history.edit(&question, "What is Go?").unwrap();
+
+        let json = export(&conversation(), &history, Format::Json, None).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let messages = json["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["content"], "What is Go?");
+    }
+
+    #[test]
+    fn json_keeps_usage_and_all_sources() {
+        let json = export(&conversation(), &history(), Format::Json, None).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(json["messages"][0].get("sources").is_none());
+        let answer = &json["messages"][1];
+        assert_eq!(answer["role"], "assistant");
+        assert_eq!(answer["cancelled"], true);
+        assert_eq!(answer["usage"]["prompt_tokens"], 100);
+        assert_eq!(answer["sources"][0]["cited"], true);
+        assert_eq!(answer["sources"][1]["cited"], false);
+        assert_eq!(answer["sources"][0]["url"], "");
+    }
+
+    #[test]
+    fn html_links_the_sources_under_base() {
+        let html = export(
+            &conversation(),
+            &history(),
+            Format::Html,
+            Some("https://x.org"),
+        )
+        .unwrap();
+        assert!(html.contains("<title>Conversation</title>"), "{}", html);
+        assert!(html.contains("https://x.org/context/rust.json"), "{}", html);
+    }
+}
@@ -0,0 +1,72 @@
+//! Dataset of rated answers for evaluation runs.
+use std::sync::Arc;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::history::{History, Rating};
+use crate::store::HistoryStore;
+
+/// One rated answer. Lines of the dataset with a thumbs up can be passed to
+/// `gpt-rs eval`, with the cited articles as the expected ones.
+#[derive(Debug, Serialize)]
+pub struct Record {
+    /// `<conversation>/<message>`
+    pub id: String,
+    pub question: String,
+    /// Articles sent to the model with the question.
+    pub context: Vec<String>,
+    /// Articles cited by the answer.
+    pub expected: Vec<String>,
+    pub answer: String,
+    pub rating: Rating,
+    pub comment: Option<String>,
+    pub rated: DateTime<Utc>,
+}
+
+/// Every rated answer of every conversation, optionally only those rated
+/// `rating`.
+pub fn dataset(store: Arc<dyn HistoryStore>, rating: Option<Rating>) -> Result<Vec<Record>> {
+    let mut records = vec![];
+    for entry in store.entries()? {
+        if entry.conversation.is_none() {
+            continue;
+        }
+        let history = History::load(store.clone(), &entry.id)?;
+        for message in history.nodes() {
+            let Some(info) = &message.info else {
+                continue;
+            };
+            let Some(feedback) = &info.feedback else {
+                continue;
+            };
+            if rating.is_some_and(|rating| rating != feedback.rating) {
+                continue;
+            }
+            let question = match &message.parent {
+                Some(parent) => history.get(parent)?.content().to_string(),
+                None => String::new(),
+            };
+            let filenames = &info.context_info.filenames;
+            records.push(Record {
+                id: format!("{}/{}", entry.id, message.id),
+                question,
+                context: filenames.iter().map(|f| f.filename.to_string()).collect(),
+                expected: info
+                    .citations
+                    .cited
+                    .iter()
+                    .filter_map(|n| filenames.get(n - 1))
+                    .map(|f| f.filename.to_string())
+                    .collect(),
+                answer: message.content().to_string(),
+                rating: feedback.rating,
+                comment: feedback.comment.clone(),
+                rated: feedback.created,
+            });
+        }
+    }
+    records.sort_by_key(|r| r.rated);
+    Ok(records)
+}
@@ -0,0 +1,173 @@
+//! Probes for orchestrators, and the status page.
+//!
+//! `/healthz` answers as long as the process serves requests. `/readyz`
+//! answers 503 until the articles can be searched and OpenAI answers, which
+//! is checked at most every `OPENAI_CHECK_INTERVAL` so that probes don't
+//! hammer it. With a replayed cassette OpenAI isn't needed. Being public,
+//! `/readyz` only answers `ok` or `not ready`; the failed checks are on
+//! `/status`, which is for the accounts of `auth.admins` only.
+use std::{
+    fs,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Router};
+use axum_sessions::extractors::WritableSession;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::api::{self, ApiError, AppState};
+use crate::html::{HtmlTemplate, StatusTemplate};
+
+const OPENAI_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct Health {
+    started: Instant,
+    /// When OpenAI was last checked and the error, if any.
+    openai: Mutex<Option<(Instant, Result<(), String>)>>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            openai: Mutex::new(None),
+        }
+    }
+}
+
+impl Health {
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(result: Result<String, String>) -> Self {
+        match result {
+            Ok(detail) => Self { ok: true, detail },
+            Err(detail) => Self { ok: false, detail },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub embeddings: Check,
+    pub data_dir: Check,
+    pub openai: Check,
+}
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/status", get(status))
+}
+
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+async fn readyz(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    if readiness(&state).await.ready {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
+
+async fn status(
+    State(state): State<Arc<AppState>>,
+    session: WritableSession,
+) -> Result<impl IntoResponse, ApiError> {
+    api::admin(&state, &session)?;
+    let embeddings = &state.bot.embeddings;
+    let articles = fs::read_dir(embeddings.data_dir())
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter(|e| e.path().extension().is_some_and(|ext| ext == "json"))
+                .count()
+        })
+        .unwrap_or(0);
+    Ok(HtmlTemplate(StatusTemplate {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime: format_duration(state.health.uptime()),
+        chat_model: state.bot.client.chat_model().to_string(),
+        embedding_model: state.bot.client.embedding_model().to_string(),
+        indexed: embeddings.count(),
+        dimensions: embeddings.dimensions(),
+        index_bytes: embeddings.count() * embeddings.dimensions() * std::mem::size_of::<f32>(),
+        articles,
+        sockets: state.hub.open_sockets(),
+        conversations: state.hub.open_rooms(),
+        readiness: readiness(&state).await,
+    }))
+}
+
+pub async fn readiness(state: &AppState) -> Readiness {
+    let embeddings = &state.bot.embeddings;
+    let indexed = match embeddings.count() {
+        0 => Err("no articles indexed".to_string()),
+        n => Ok(format!("{} articles", n)),
+    };
+    let data_dir = fs::read_dir(embeddings.data_dir())
+        .map(|_| embeddings.data_dir().display().to_string())
+        .map_err(|e| format!("{}: {}", embeddings.data_dir().display(), e));
+    let openai = if state.bot.client.offline() {
+        Ok("offline, replaying the cassette".to_string())
+    } else {
+        openai(state).await.map(|()| "reachable".to_string())
+    };
+
+    Readiness {
+        ready: indexed.is_ok() && data_dir.is_ok() && openai.is_ok(),
+        embeddings: Check::new(indexed),
+        data_dir: Check::new(data_dir),
+        openai: Check::new(openai),
+    }
+}
+
+/// The last result of checking OpenAI, checking again once it is older than
+/// `OPENAI_CHECK_INTERVAL`.
+async fn openai(state: &AppState) -> Result<(), String> {
+    let health = &state.health;
+    if let Some((checked, result)) = &*health.openai.lock().unwrap_or_else(|e| e.into_inner()) {
+        if checked.elapsed() < OPENAI_CHECK_INTERVAL {
+            return result.clone();
+        }
+    }
+    let result = state.bot.client.ping().await.map_err(|e| e.to_string());
+    if let Err(e) = &result {
+        warn!("OpenAI isn't reachable: {}", e);
+    }
+    *health.openai.lock().unwrap_or_else(|e| e.into_inner()) =
+        Some((Instant::now(), result.clone()));
+    result
+}
+
+/// E.g. `2d 3h 4m 5s`, leaving out leading zero units.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let units = [
+        (secs / 86400, "d"),
+        (secs / 3600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+        (secs % 60, "s"),
+    ];
+    let first = units[..3].iter().position(|(n, _)| *n > 0).unwrap_or(3);
+    units[first..]
+        .iter()
+        .map(|(n, unit)| format!("{}{}", n, unit))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
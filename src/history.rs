@@ -1,23 +1,30 @@
-use std::{
-    fs::OpenOptions,
-    io::{BufRead, Write},
-    path::Path,
-};
-//use tracing::error;
-use std::println as error;
-
-use anyhow::Result;
+use std::sync::Arc;
+use tracing::error;
+
+use anyhow::{anyhow, bail, Result};
 use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionResponseMessage, Role};
+use chrono::{DateTime, Utc};
 use derive_builder::Builder;
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
-//use tiktoken_rs::async_openai::num_tokens_from_messages;
 
-use crate::{embeddings::ContextInfo, CHAT_MODEL, HISTORY_DIR, MAX_HISTORY};
+use crate::bot::ToolCallInfo;
+use crate::citations::Citations;
+use crate::embeddings::ContextInfo;
+use crate::session::random_id;
+use crate::store::HistoryStore;
+use crate::usage::{Totals, Usage};
+
+/// Tokens the chat format adds to every message.
+const MESSAGE_OVERHEAD: usize = 4;
 
+/// Messages form a tree: editing a question or regenerating an answer adds a
+/// sibling instead of replacing it. `messages` is the active branch, the path
+/// from the first message to the current one.
 pub struct History<'a> {
     pub name: Option<String>,
+    store: Option<Arc<dyn HistoryStore>>,
+    /// Every message of the conversation in the order they were stored.
+    nodes: Vec<Message<'a>>,
     messages: Vec<Message<'a>>,
 }
 
@@ -26,6 +33,19 @@ pub struct Message<'a> {
     pub msg: ChatCompletionRequestMessage,
     pub tokens: u16,
     pub info: Option<Info<'a>>,
+    /// Not recorded by histories written before timestamps were added.
+    #[serde(default)]
+    pub created: Option<DateTime<Utc>>,
+    /// Assigned when the message is added to a history.
+    #[serde(default)]
+    pub id: String,
+    /// The message this one follows, `None` for the first message.
+    #[serde(default)]
+    pub parent: Option<String>,
+    /// An answer stopped by the user; `msg` holds the part received until
+    /// then. Left out of the prompts of later questions.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cancelled: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Builder)]
@@ -34,21 +54,124 @@ pub struct Info<'a> {
     pub user_message_tokens: u64,
     pub history_count: usize,
     pub history_size: u64,
+    #[serde(default)]
+    pub usage: Usage,
+    #[serde(default)]
+    pub cost: f64,
+    /// Totals of the whole session, including this message.
+    #[serde(default)]
+    pub session_total: Totals,
+    /// Tools called by the model in the tools chat mode.
+    #[serde(default)]
+    #[builder(default)]
+    pub tool_calls: Vec<ToolCallInfo<'a>>,
+    /// Articles of `context_info` cited by the answer.
+    #[serde(default)]
+    #[builder(default)]
+    pub citations: Citations,
+    /// Number of earlier messages replaced by a summary.
+    #[serde(default)]
+    #[builder(default)]
+    pub summarized: usize,
+    /// Summary written while answering, used instead of the older messages
+    /// from then on.
+    #[serde(default)]
+    #[builder(default)]
+    pub summary: Option<Summary>,
+    /// Rating of the answer by the user, stored separately and attached on
+    /// load.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[builder(default)]
+    pub feedback: Option<Feedback>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rating {
+    Up,
+    Down,
+}
+
+impl std::str::FromStr for Rating {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "up" => Rating::Up,
+            "down" => Rating::Down,
+            _ => bail!("Unknown rating {}, use up or down", s),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Feedback {
+    pub rating: Rating,
+    #[serde(default)]
+    pub comment: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+/// Model-written summary of the first `covers` messages of a history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Summary {
+    pub covers: usize,
+    pub content: String,
+    pub tokens: u16,
+}
+
+/// The part of a history sent to the model.
+pub struct Window<'h, 'a> {
+    /// Summary of the messages before the window.
+    pub summary: Option<&'h Summary>,
+    /// Messages which no longer fit and have to be added to `summary`.
+    pub to_summarize: &'h [Message<'a>],
+    pub messages: &'h [Message<'a>],
+    /// Index of the first message of the window.
+    pub start: usize,
+}
+
+/// Tokens taken by a chat message with `text` as content.
+pub fn count_tokens(text: &str) -> u16 {
+    let tokens = tiktoken_rs::cl100k_base_singleton()
+        .lock()
+        .encode_with_special_tokens(text)
+        .len();
+    u16::try_from(tokens + MESSAGE_OVERHEAD).unwrap_or(u16::MAX)
 }
 
 impl<'a> Message<'a> {
+    pub fn new(role: Role, text: &str) -> Self {
+        Message {
+            msg: ChatCompletionRequestMessage {
+                role,
+                content: text.to_string(),
+                name: None,
+            },
+            tokens: count_tokens(text),
+            info: None,
+            created: Some(Utc::now()),
+            id: String::new(),
+            parent: None,
+            cancelled: false,
+        }
+    }
+
     pub fn user(text: &str) -> Result<Self> {
         let msg = ChatCompletionRequestMessage {
             role: Role::User,
             content: text.to_string(),
             name: None,
         };
-        //let tokens = num_tokens_from_messages(CHAT_MODEL, &[msg.clone()])? as u16;
-        let tokens = 0;
+        let tokens = count_tokens(&msg.content);
         Ok(Message {
             msg,
             tokens,
             info: None,
+            created: Some(Utc::now()),
+            id: String::new(),
+            parent: None,
+            cancelled: false,
         })
     }
     pub fn from_response(resp: ChatCompletionResponseMessage, info: Info<'a>) -> Result<Self> {
@@ -57,15 +180,26 @@ impl<'a> Message<'a> {
             content: resp.content,
             name: None,
         };
-        //let tokens = num_tokens_from_messages(CHAT_MODEL, &[msg.clone()])? as u16;
-        let tokens = 0;
+        let tokens = count_tokens(&msg.content);
         Ok(Message {
             msg,
             tokens,
             info: Some(info),
+            created: Some(Utc::now()),
+            id: String::new(),
+            parent: None,
+            cancelled: false,
         })
     }
 
+    /// An answer stopped after `partial` was received.
+    pub fn cancelled(partial: &str) -> Self {
+        Message {
+            cancelled: true,
+            ..Message::new(Role::Assistant, partial)
+        }
+    }
+
     pub fn class(&self) -> &'static str {
         match self.msg.role {
             Role::User => "user",
@@ -84,99 +218,413 @@ impl<'a> Message<'a> {
     pub fn content(&self) -> &str {
         &self.msg.content
     }
+
+    /// Histories written before tokens were counted have 0 stored.
+    pub fn token_count(&self) -> u16 {
+        match self.tokens {
+            0 => count_tokens(self.content()),
+            tokens => tokens,
+        }
+    }
 }
 
 impl<'a> History<'a> {
-    pub fn new() -> Self {
-        let name: String = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(24)
-            .map(char::from)
-            .collect();
-
-        History::load(&name)
-            .map_err(|e| error!("Cannot create history file {}", e))
-            .unwrap_or(History {
-                name: None,
-                messages: vec![],
-            })
+    /// History which is never stored.
+    pub fn in_memory() -> Self {
+        History {
+            name: None,
+            store: None,
+            nodes: vec![],
+            messages: vec![],
+        }
     }
 
-    pub fn load(name: &str) -> Result<Self> {
-        let dir = Path::new(HISTORY_DIR);
-        let filename = dir.join(name);
-
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&filename)?;
-
-        let reader = std::io::BufReader::new(file);
-        let mut messages: Vec<Message> = Vec::new();
-
-        for line in reader.lines() {
-            let line = line?;
-            let message: Message = serde_json::from_str(&line)?;
-            messages.push(message);
+    /// Loads the history with the most recently added message as the
+    /// active branch.
+    pub fn load(store: Arc<dyn HistoryStore>, name: &str) -> Result<Self> {
+        let mut nodes = store.messages(name)?;
+        for idx in 0..nodes.len() {
+            // Histories written before branching are linear and have no ids
+            if nodes[idx].id.is_empty() {
+                nodes[idx].id = idx.to_string();
+                nodes[idx].parent = idx.checked_sub(1).map(|p| nodes[p].id.clone());
+            }
         }
-
-        Ok(Self {
-            messages,
+        let mut feedback = store.feedback(name)?;
+        for message in &mut nodes {
+            if let (Some(info), Some(feedback)) = (&mut message.info, feedback.remove(&message.id))
+            {
+                info.feedback = Some(feedback);
+            }
+        }
+        let mut history = Self {
             name: Some(name.to_string()),
-        })
+            store: Some(store),
+            nodes,
+            messages: vec![],
+        };
+        history.checkout(history.nodes.last().map(|m| m.id.clone()));
+        Ok(history)
     }
 
     pub fn save(&mut self, message: &Message<'a>) -> Result<()> {
-        if let Some(name) = &self.name {
-            let dir = Path::new(HISTORY_DIR);
-            let filename = dir.join(name);
-            let mut file = OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(filename)?;
-
-            serde_json::to_writer(&file, message)?;
-            file.write_all(b"\n")?;
-            file.flush()?;
+        if let (Some(name), Some(store)) = (&self.name, &self.store) {
+            store.append_message(name, message)?;
         }
         Ok(())
     }
 
-    pub fn user(&mut self, message: Message<'a>) {
+    /// Appends `message` to the active branch.
+    pub fn push(&mut self, mut message: Message<'a>) {
+        message.id = random_id();
+        message.parent = self.messages.last().map(|m| m.id.clone());
         if let Err(e) = self.save(&message) {
             error!("Couldn't save history: {} file {:?}", e, self.name);
         }
+        self.nodes.push(message.clone());
         self.messages.push(message);
     }
+
+    pub fn user(&mut self, message: Message<'a>) {
+        self.push(message);
+    }
+
     pub fn assistant(&mut self, message: Message<'a>) {
-        if let Err(e) = self.save(&message) {
-            error!("Couldn't save history: {} file {:?}", e, self.name);
-        }
-        self.messages.push(message);
+        self.push(message);
     }
 
-    pub fn messages(&self) -> &[Message] {
+    /// Messages of the active branch.
+    pub fn messages(&self) -> &[Message<'a>] {
         &self.messages
     }
 
-    pub fn prune_history(&self) -> &[Message] {
-        for i in self.messages.len()..0 {
-            if self.messages[i..]
-                .iter()
-                .map(|m| m.tokens as u64)
-                .sum::<u64>()
-                > MAX_HISTORY.into()
-            {
-                return &self.messages[i + 1..];
+    pub fn last(&self) -> Option<&Message<'a>> {
+        self.messages.last()
+    }
+
+    pub fn get(&self, id: &str) -> Result<&Message<'a>> {
+        self.nodes
+            .iter()
+            .find(|m| m.id == id)
+            .ok_or_else(|| anyhow!("No message {}", id))
+    }
+
+    /// Ids of the messages following the same parent as `id`, `id` included,
+    /// oldest first.
+    pub fn siblings(&self, id: &str) -> Vec<&str> {
+        let Ok(message) = self.get(id) else {
+            return vec![];
+        };
+        self.nodes
+            .iter()
+            .filter(|m| m.parent == message.parent)
+            .map(|m| m.id.as_str())
+            .collect()
+    }
+
+    /// Every message, in the order they were added.
+    pub fn nodes(&self) -> &[Message<'a>] {
+        &self.nodes
+    }
+
+    /// Records the user's rating of answer `id`, replacing an earlier one.
+    pub fn feedback(
+        &mut self,
+        id: &str,
+        rating: Rating,
+        comment: Option<String>,
+    ) -> Result<Feedback> {
+        if self.get(id)?.msg.role != Role::Assistant {
+            bail!("Only answers can be rated");
+        }
+        let feedback = Feedback {
+            rating,
+            comment: comment.filter(|c| !c.trim().is_empty()),
+            created: Utc::now(),
+        };
+        if let (Some(name), Some(store)) = (&self.name, &self.store) {
+            store.save_feedback(name, id, &feedback)?;
+        }
+        let messages = self.nodes.iter_mut().chain(self.messages.iter_mut());
+        for message in messages.filter(|m| m.id == id) {
+            if let Some(info) = &mut message.info {
+                info.feedback = Some(feedback.clone());
             }
         }
-        &self.messages
+        Ok(feedback)
+    }
+
+    /// Starts a new branch with `text` replacing the user message `id`.
+    pub fn edit(&mut self, id: &str, text: &str) -> Result<()> {
+        let message = self.get(id)?;
+        if message.msg.role != Role::User {
+            bail!("Only questions can be edited");
+        }
+        self.checkout(message.parent.clone());
+        self.user(Message::user(text)?);
+        Ok(())
+    }
+
+    /// Makes the question answered by `id` the end of the active branch, so
+    /// that a new answer becomes a sibling of `id`.
+    pub fn regenerate(&mut self, id: &str) -> Result<()> {
+        let message = self.get(id)?;
+        if message.msg.role != Role::Assistant {
+            bail!("Only answers can be regenerated");
+        }
+        self.checkout(message.parent.clone());
+        Ok(())
+    }
+
+    /// Activates the branch containing `id`, continued by its latest replies.
+    pub fn switch(&mut self, id: &str) -> Result<()> {
+        let mut leaf = self.get(id)?.id.clone();
+        while let Some(child) = self
+            .nodes
+            .iter()
+            .rev()
+            .find(|m| m.parent.as_deref() == Some(leaf.as_str()))
+        {
+            leaf = child.id.clone();
+        }
+        self.checkout(Some(leaf));
+        Ok(())
+    }
+
+    /// Makes the path from the first message to `leaf` the active branch.
+    fn checkout(&mut self, leaf: Option<String>) {
+        let mut path = vec![];
+        let mut next = leaf;
+        while let Some(id) = next {
+            let Some(message) = self.nodes.iter().find(|m| m.id == id) else {
+                break;
+            };
+            // A longer path can only come from a cycle in a corrupted history
+            if path.len() >= self.nodes.len() {
+                break;
+            }
+            next = message.parent.clone();
+            path.push(message.clone());
+        }
+        path.reverse();
+        self.messages = path;
+    }
+
+    /// The latest summary written for this history.
+    pub fn summary(&self) -> Option<&Summary> {
+        self.messages
+            .iter()
+            .rev()
+            .find_map(|m| m.info.as_ref()?.summary.as_ref())
+            .filter(|s| s.covers < self.messages.len())
+    }
+
+    /// The most recent messages fitting into `max_history` tokens. When
+    /// older messages have to be left out, they are replaced by a summary
+    /// of at most `summary_size` tokens; if the stored summary doesn't cover
+    /// them yet, the window is shrunk to half the budget so that the new
+    /// summary lasts for a few turns. The last message is always included.
+    pub fn window(&self, max_history: u16, summary_size: u16) -> Window<'_, 'a> {
+        let start = self.fitting(max_history.into());
+        if start == 0 {
+            return Window {
+                summary: None,
+                to_summarize: &[],
+                messages: &self.messages,
+                start,
+            };
+        }
+
+        let summary = self.summary();
+        let covered = summary.map_or(0, |s| s.covers);
+        let budget = u64::from(max_history.saturating_sub(summary_size));
+        if covered >= self.fitting(budget) {
+            return Window {
+                summary,
+                to_summarize: &[],
+                messages: &self.messages[covered..],
+                start: covered,
+            };
+        }
+        let start = self.fitting(budget / 2).max(covered);
+        Window {
+            summary,
+            to_summarize: &self.messages[covered..start],
+            messages: &self.messages[start..],
+            start,
+        }
+    }
+
+    /// Start of the longest tail of messages within `budget` tokens.
+    fn fitting(&self, budget: u64) -> usize {
+        let mut total = 0;
+        let mut start = self.messages.len();
+        while start > 0 {
+            let tokens = u64::from(self.messages[start - 1].token_count());
+            if total + tokens > budget && start < self.messages.len() {
+                break;
+            }
+            total += tokens;
+            start -= 1;
+        }
+        start
     }
 }
 
-impl<'a> Default for History<'a> {
-    fn default() -> Self {
-        Self::new()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A message of `tokens` tokens.
+    fn message(role: Role, tokens: u16) -> Message<'static> {
+        Message {
+            tokens,
+            ..Message::new(role, "text")
+        }
+    }
+
+    fn with_summary(mut message: Message<'static>, covers: usize) -> Message<'static> {
+        let info = serde_json::json!({
+            "context_info": { "filenames": [], "size": 0 },
+            "user_message_tokens": 0,
+            "history_count": 0,
+            "history_size": 0,
+            "summary": { "covers": covers, "content": "summary", "tokens": 10 },
+        });
+        message.info = Some(serde_json::from_value(info).unwrap());
+        message
+    }
+
+    /// A history of `count` messages of 10 tokens each.
+    fn history(count: usize) -> History<'static> {
+        let mut history = History::in_memory();
+        for i in 0..count {
+            let role = if i % 2 == 0 {
+                Role::User
+            } else {
+                Role::Assistant
+            };
+            history.push(message(role, 10));
+        }
+        history
+    }
+
+    fn id(history: &History<'_>, idx: usize) -> String {
+        history.messages()[idx].id.clone()
+    }
+
+    fn contents(history: &History<'_>) -> Vec<String> {
+        history
+            .messages()
+            .iter()
+            .map(|m| m.content().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn fitting_keeps_the_last_message() {
+        let mut history = History::in_memory();
+        history.push(message(Role::User, 10));
+        history.push(message(Role::Assistant, 100));
+        assert_eq!(history.fitting(50), 1);
+        assert_eq!(history.fitting(110), 0);
+        assert_eq!(History::in_memory().fitting(10), 0);
+    }
+
+    #[test]
+    fn window_with_everything() {
+        let history = history(4);
+        let window = history.window(40, 10);
+        assert_eq!(window.start, 0);
+        assert_eq!(window.messages.len(), 4);
+        assert!(window.summary.is_none() && window.to_summarize.is_empty());
+    }
+
+    #[test]
+    fn window_summarizes_to_half_the_budget() {
+        let history = history(10);
+        let window = history.window(50, 10);
+        assert!(window.summary.is_none());
+        assert_eq!(window.to_summarize.len(), 8);
+        assert_eq!(window.start, 8);
+        assert_eq!(window.messages.len(), 2);
+    }
+
+    #[test]
+    fn window_uses_the_stored_summary() {
+        let mut history = History::in_memory();
+        for i in 0..10 {
+            let role = if i % 2 == 0 {
+                Role::User
+            } else {
+                Role::Assistant
+            };
+            let message = message(role, 10);
+            history.push(if i == 7 {
+                with_summary(message, 7)
+            } else {
+                message
+            });
+        }
+        let window = history.window(50, 10);
+        assert_eq!(window.summary.map(|s| s.covers), Some(7));
+        assert!(window.to_summarize.is_empty());
+        assert_eq!(window.start, 7);
+        assert_eq!(window.messages.len(), 3);
+
+        // Too old to be enough: the messages since are summarized as well
+        let window = history.window(30, 10);
+        assert_eq!(window.start, 9);
+        assert_eq!(window.to_summarize.len(), 2);
+    }
+
+    #[test]
+    fn edit_regenerate_and_switch_branches() {
+        let mut history = History::in_memory();
+        for text in ["q1", "a1", "q2", "a2"] {
+            let role = if text.starts_with('q') {
+                Role::User
+            } else {
+                Role::Assistant
+            };
+            history.push(Message::new(role, text));
+        }
+        let (a1, q2, a2) = (id(&history, 1), id(&history, 2), id(&history, 3));
+
+        history.edit(&q2, "q2'").unwrap();
+        assert_eq!(contents(&history), ["q1", "a1", "q2'"]);
+        assert_eq!(history.siblings(&q2).len(), 2);
+
+        history.switch(&q2).unwrap();
+        assert_eq!(contents(&history), ["q1", "a1", "q2", "a2"]);
+
+        history.regenerate(&a2).unwrap();
+        assert_eq!(contents(&history), ["q1", "a1", "q2"]);
+        history.assistant(Message::new(Role::Assistant, "a2'"));
+        assert_eq!(history.siblings(&a2).len(), 2);
+
+        // The latest replies continue the branch
+        history.switch(&a1).unwrap();
+        assert_eq!(contents(&history), ["q1", "a1", "q2'"]);
+        assert_eq!(history.nodes().len(), 6);
+
+        assert!(history.edit(&a1, "text").is_err());
+        assert!(history.regenerate(&q2).is_err());
+        assert!(history.switch("unknown").is_err());
+    }
+
+    #[test]
+    fn checkout_stops_at_cycles() {
+        let mut history = History::in_memory();
+        for (id, parent) in [("a", "b"), ("b", "a")] {
+            let mut message = Message::new(Role::User, id);
+            message.id = id.to_string();
+            message.parent = Some(parent.to_string());
+            history.nodes.push(message);
+        }
+        history.checkout(Some("a".to_string()));
+        assert_eq!(contents(&history), ["b", "a"]);
+        history.checkout(None);
+        assert!(history.messages().is_empty());
     }
 }
@@ -6,14 +6,68 @@ use axum::response::{Html, Response};
 use axum::response::IntoResponse;
 use serde::Serialize;
 
-#[derive(Debug, Serialize)]
+use crate::citations::{segments, Segment};
+use crate::history::Rating;
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Message {
     #[serde(rename = "type")]
     pub typ: String,
     pub prefix: String,
     pub class: String,
     pub content: String,
+    /// Escaped content with citations linked to their articles.
+    pub html: String,
+    pub warning: String,
     pub info: String,
+    pub id: String,
+    /// Set when the message has siblings, i.e. it was edited or regenerated.
+    pub branch: Option<Branch>,
+    /// `up` or `down` once the user rated the answer.
+    pub rating: String,
+}
+
+/// Position of a message among its siblings, for switching branches.
+#[derive(Debug, Clone, Serialize)]
+pub struct Branch {
+    /// From 1.
+    pub index: usize,
+    pub count: usize,
+    pub prev: Option<String>,
+    pub next: Option<String>,
+}
+
+impl Message {
+    /// Renders `message` of `history` with its branch position.
+    pub fn in_history(
+        history: &crate::history::History,
+        message: &crate::history::Message,
+    ) -> Self {
+        let mut html = Message::from(message);
+        let siblings = history.siblings(&message.id);
+        if siblings.len() > 1 {
+            let idx = siblings
+                .iter()
+                .position(|id| *id == message.id)
+                .unwrap_or(0);
+            html.branch = Some(Branch {
+                index: idx + 1,
+                count: siblings.len(),
+                prev: idx.checked_sub(1).map(|i| siblings[i].to_string()),
+                next: siblings.get(idx + 1).map(|id| id.to_string()),
+            });
+        }
+        html
+    }
+}
+
+/// The active branch of `history`.
+pub fn history_messages(history: &crate::history::History) -> Vec<Message> {
+    history
+        .messages()
+        .iter()
+        .map(|m| Message::in_history(history, m))
+        .collect()
 }
 
 #[derive(Template)]
@@ -40,25 +94,152 @@ impl From<&crate::history::Message<'_>> for Message {
 
         let class = typ.clone();
         let content = value.msg.content.clone();
+        let html = match value.msg.role {
+            Role::Assistant => render_content(value, Some("")),
+            Role::User | Role::System => line_breaks(&escape(&value.msg.content)),
+        };
+        let warning = if value.cancelled {
+            "Stopped before the answer was complete".to_string()
+        } else {
+            value
+                .info
+                .as_ref()
+                .and_then(|info| info.citations.warning())
+                .unwrap_or_default()
+        };
         let info = value
             .info
             .as_ref()
             .map(|info| Info { info }.render().unwrap())
             .unwrap_or_default();
+        let rating = value
+            .info
+            .as_ref()
+            .and_then(|info| info.feedback.as_ref())
+            .map(|feedback| match feedback.rating {
+                Rating::Up => "up",
+                Rating::Down => "down",
+            })
+            .unwrap_or_default()
+            .to_string();
         Message {
             typ,
             prefix,
             class,
             content,
+            html,
+            warning,
             info,
+            id: value.id.clone(),
+            branch: None,
+            rating,
+        }
+    }
+}
+
+/// Renders the content of an assistant message as HTML, linking citations
+/// `[n]` to the cited articles under `base`, the URL of the server. Without
+/// `base` citations aren't linked.
+pub fn render_content(msg: &crate::history::Message, base: Option<&str>) -> String {
+    let sources = msg
+        .info
+        .as_ref()
+        .map(|info| info.context_info.filenames.as_slice())
+        .unwrap_or_default();
+
+    let mut html = String::new();
+    for segment in segments(msg.content()) {
+        match segment {
+            Segment::Text(text) => html.push_str(&escape(text)),
+            Segment::Citation(n) => match (n.checked_sub(1).and_then(|i| sources.get(i)), base) {
+                (Some(source), Some(base)) => html.push_str(&format!(
+                    "<a class=\"citation\" href=\"{}/context/{}\" target=\"_blank\" title=\"{}\">[{}]</a>",
+                    escape(base),
+                    escape(&source.filename),
+                    escape(&source.filename),
+                    n
+                )),
+                (Some(source), None) => html.push_str(&format!(
+                    "<span class=\"citation\" title=\"{}\">[{}]</span>",
+                    escape(&source.filename),
+                    n
+                )),
+                (None, _) => html.push_str(&format!("<span class=\"citation invalid\">[{}]</span>", n)),
+            },
         }
     }
+    line_breaks(&html)
+}
+
+fn line_breaks(html: &str) -> String {
+    html.replace("\r\n", "<br>").replace(['\n', '\r'], "<br>")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#039;")
 }
 
 #[derive(Template)]
 #[template(path = "index.html")]
 pub struct IndexTemplate {
     pub history: Vec<Message>,
+    /// Sequence number of the last message, see `websocket`.
+    pub seq: usize,
+    pub conversations: Vec<crate::conversations::Conversation>,
+    /// Id of the open conversation.
+    pub current: String,
+    /// Name of the logged in user, `None` without `auth.enabled`.
+    pub username: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "login.html")]
+pub struct LoginTemplate {
+    pub username: String,
+    pub error: String,
+    /// Label of the OIDC login button, if configured.
+    pub oidc: Option<String>,
+}
+
+/// Standalone page of an exported conversation.
+#[derive(Template)]
+#[template(path = "export.html")]
+pub struct ExportTemplate<'a> {
+    pub title: String,
+    pub conversation: &'a crate::conversations::Conversation,
+    pub messages: Vec<ExportMessage>,
+}
+
+pub struct ExportMessage {
+    pub message: Message,
+    pub created: String,
+    /// Cited articles.
+    pub sources: Vec<crate::citations::Source>,
+}
+
+/// The `/status` page.
+#[derive(Template)]
+#[template(path = "status.html")]
+pub struct StatusTemplate {
+    pub version: &'static str,
+    pub uptime: String,
+    pub chat_model: String,
+    pub embedding_model: String,
+    /// Articles in the embeddings index.
+    pub indexed: usize,
+    pub dimensions: usize,
+    /// Memory taken by the embeddings.
+    pub index_bytes: usize,
+    /// Article files in the data directory.
+    pub articles: usize,
+    pub sockets: usize,
+    /// Conversations with sockets attached.
+    pub conversations: usize,
+    pub readiness: crate::health::Readiness,
 }
 
 pub struct HtmlTemplate<T>(pub T);
@@ -78,3 +259,46 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Message as HistoryMessage;
+
+    fn answer(text: &str) -> HistoryMessage<'static> {
+        let info = serde_json::json!({
+            "context_info": { "filenames": [{ "filename": "a<b>.json", "score": 0.9 }], "size": 0 },
+            "user_message_tokens": 0,
+            "history_count": 0,
+            "history_size": 0,
+        });
+        HistoryMessage {
+            info: Some(serde_json::from_value(info).unwrap()),
+            ..HistoryMessage::new(Role::Assistant, text)
+        }
+    }
+
+    #[test]
+    fn render_content_links_citations_under_base() {
+        let message = answer("See [1] & [2]\nbye");
+        assert_eq!(
+            render_content(&message, Some("https://x.org")),
+            "See <a class=\"citation\" href=\"https://x.org/context/a&lt;b&gt;.json\" \
+             target=\"_blank\" title=\"a&lt;b&gt;.json\">[1]</a> &amp; \
+             <span class=\"citation invalid\">[2]</span><br>bye"
+        );
+        assert_eq!(
+            render_content(&message, None),
+            "See <span class=\"citation\" title=\"a&lt;b&gt;.json\">[1]</span> &amp; \
+             <span class=\"citation invalid\">[2]</span><br>bye"
+        );
+    }
+
+    #[test]
+    fn questions_are_shown_as_written() {
+        let question = HistoryMessage::new(Role::User, "What is [1] <b>?\nthanks");
+        let html = Message::from(&question);
+        assert_eq!(html.html, "What is [1] &lt;b&gt;?<br>thanks");
+        assert_eq!(html.prefix, "You: ");
+    }
+}
@@ -0,0 +1,746 @@
+//! One writer per open conversation.
+//!
+//! All sockets attached to a conversation share a `Room`: its commands are
+//! applied to a single `History` by the room's task, one after the other, and
+//! every frame they cause is broadcast to the sockets of the room. API
+//! requests changing a conversation go through the same room.
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::api::AppState;
+use crate::bot::Bot;
+use crate::conversations::Conversation;
+use crate::history::{Feedback, History, Message};
+use crate::html::{self, Message as HTMLMsg};
+use crate::usage::Usage;
+use crate::websocket::{ClientCommand, ErrorCode, ServerEvent, ServerFrame, State};
+
+/// Frames kept for sockets which fall behind; a socket missing some gets the
+/// whole history again.
+const EVENTS: usize = 1024;
+
+/// Origin of commands which don't come from a socket.
+const NO_SOCKET: u64 = 0;
+
+/// A frame for the sockets of a room.
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// Socket of the command the frame belongs to.
+    origin: u64,
+    /// Only for `origin`, e.g. the ack of its command.
+    private: bool,
+    frame: ServerFrame,
+}
+
+impl Event {
+    /// The frame to send to socket `id`, if any. Only the socket which sent a
+    /// command gets the `request` of its frames.
+    pub fn for_socket(self, id: u64) -> Option<ServerFrame> {
+        let mut frame = self.frame;
+        if self.origin != id {
+            if self.private {
+                return None;
+            }
+            frame.request = None;
+        }
+        Some(frame)
+    }
+}
+
+/// A command the history can't apply, e.g. editing a message which doesn't
+/// exist, as opposed to a failure while answering.
+#[derive(Debug)]
+pub struct Rejected(pub anyhow::Error);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// What a command run with `Room::run` did.
+#[derive(Debug, Default)]
+pub struct Outcome {
+    /// The active branch afterwards.
+    pub messages: Vec<HTMLMsg>,
+    /// Usage of the answer written by the command.
+    pub usage: Option<Usage>,
+    pub feedback: Option<Feedback>,
+}
+
+enum Input {
+    Command {
+        command: ClientCommand,
+        request: Option<String>,
+        origin: u64,
+        /// Position in the queue of the room, which cancels refer to.
+        number: u64,
+        /// Set by `Room::run`, which gets the outcome instead of frames.
+        done: Option<oneshot::Sender<Result<Outcome>>>,
+    },
+    /// Send the history to socket `origin`, which missed frames.
+    Resync { origin: u64 },
+    /// Stop once the commands queued before are done, e.g. before the
+    /// conversation is deleted.
+    Close,
+}
+
+pub struct Room {
+    inputs: UnboundedSender<Input>,
+    /// Number of the last command queued.
+    queued: AtomicU64,
+    /// Numbers of the last command queued when answers were cancelled.
+    cancel: UnboundedSender<u64>,
+    events: broadcast::Sender<Event>,
+    answering: Arc<AtomicBool>,
+}
+
+impl Room {
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Queues `command` of socket `origin`. Its frames are broadcast, ending
+    /// with an ack or error for `origin` alone.
+    pub fn send(&self, origin: u64, request: Option<String>, command: ClientCommand) -> Result<()> {
+        self.input(Input::Command {
+            command,
+            request,
+            origin,
+            number: self.next(),
+            done: None,
+        })
+    }
+
+    /// Runs `command` for a caller without a socket and waits for it.
+    pub async fn run(&self, command: ClientCommand) -> Result<Outcome> {
+        let (done, outcome) = oneshot::channel();
+        self.input(Input::Command {
+            command,
+            request: None,
+            origin: NO_SOCKET,
+            number: self.next(),
+            done: Some(done),
+        })?;
+        outcome
+            .await
+            .map_err(|_| anyhow!("The conversation was closed"))?
+    }
+
+    /// Whether an answer is being written.
+    pub fn answering(&self) -> bool {
+        self.answering.load(Ordering::Relaxed)
+    }
+
+    /// Stops the answer being written, if any, and those of the commands
+    /// queued so far.
+    pub fn cancel(&self) {
+        let _ = self.cancel.send(self.queued.load(Ordering::Relaxed));
+    }
+
+    pub fn resync(&self, origin: u64) -> Result<()> {
+        self.input(Input::Resync { origin })
+    }
+
+    /// Cancels the answers and closes the room once the commands queued so
+    /// far are done; later commands fail.
+    fn close(&self) {
+        self.cancel();
+        let _ = self.input(Input::Close);
+    }
+
+    fn next(&self) -> u64 {
+        self.queued.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn input(&self, input: Input) -> Result<()> {
+        self.inputs
+            .send(input)
+            .map_err(|_| anyhow!("The conversation was closed"))
+    }
+}
+
+struct Entry {
+    room: Weak<Room>,
+    writer: JoinHandle<()>,
+}
+
+/// The rooms of the open conversations.
+#[derive(Default)]
+pub struct Hub {
+    rooms: Mutex<HashMap<String, Entry>>,
+    sockets: AtomicU64,
+    open_sockets: AtomicUsize,
+}
+
+impl Hub {
+    /// A new socket id, never `NO_SOCKET`. The socket counts as open until
+    /// `close_socket`.
+    pub fn socket_id(&self) -> u64 {
+        self.open_sockets.fetch_add(1, Ordering::Relaxed);
+        self.sockets.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn close_socket(&self) {
+        self.open_sockets.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn open_sockets(&self) -> usize {
+        self.open_sockets.load(Ordering::Relaxed)
+    }
+
+    /// Conversations with sockets or API requests attached.
+    pub fn open_rooms(&self) -> usize {
+        let rooms = self.rooms.lock().unwrap_or_else(|e| e.into_inner());
+        rooms.values().filter(|e| e.room.strong_count() > 0).count()
+    }
+
+    /// Closes the room of conversation `id`, if any, and waits until it
+    /// stored its last message.
+    pub async fn close(&self, id: &str) {
+        let entry = {
+            let mut rooms = self.rooms.lock().unwrap_or_else(|e| e.into_inner());
+            rooms.remove(id)
+        };
+        let Some(entry) = entry else {
+            return;
+        };
+        if let Some(room) = entry.room.upgrade() {
+            room.close();
+        }
+        let _ = entry.writer.await;
+    }
+
+    /// Runs `f` unless conversation `id` has an open room; none is opened
+    /// until `f` returns.
+    pub fn unless_open<T>(&self, id: &str, f: impl FnOnce() -> T) -> Option<T> {
+        let rooms = self.rooms.lock().unwrap_or_else(|e| e.into_inner());
+        let open = rooms
+            .get(id)
+            .is_some_and(|e| e.room.strong_count() > 0 || !e.writer.is_finished());
+        if open {
+            return None;
+        }
+        Some(f())
+    }
+
+    /// The room of `conversation`, opened if needed. It is closed once the
+    /// last `Room` is dropped and its command is finished.
+    pub fn attach(&self, state: &Arc<AppState>, conversation: &Conversation) -> Arc<Room> {
+        let mut rooms = self.rooms.lock().unwrap_or_else(|e| e.into_inner());
+        rooms.retain(|_, entry| entry.room.strong_count() > 0 || !entry.writer.is_finished());
+        if let Some(room) = rooms.get(&conversation.id).and_then(|e| e.room.upgrade()) {
+            return room;
+        }
+
+        // A closed room may still be storing its last answer
+        let previous = rooms.remove(&conversation.id).map(|entry| entry.writer);
+        let (inputs, input_rx) = mpsc::unbounded_channel();
+        let (cancel, cancel_rx) = mpsc::unbounded_channel();
+        let (events, _) = broadcast::channel(EVENTS);
+        let answering = Arc::new(AtomicBool::new(false));
+        let room = Arc::new(Room {
+            inputs,
+            queued: AtomicU64::new(0),
+            cancel,
+            events: events.clone(),
+            answering: answering.clone(),
+        });
+        let span = info_span!(parent: None, "room", conversation = %conversation.id);
+        let writer = tokio::spawn(
+            write(
+                state.clone(),
+                conversation.clone(),
+                previous,
+                input_rx,
+                Cancels::new(cancel_rx),
+                events,
+                answering,
+            )
+            .instrument(span),
+        );
+        rooms.insert(
+            conversation.id.clone(),
+            Entry {
+                room: Arc::downgrade(&room),
+                writer,
+            },
+        );
+        room
+    }
+}
+
+/// Applies the commands of a room to its history until it is closed.
+async fn write(
+    state: Arc<AppState>,
+    conversation: Conversation,
+    previous: Option<JoinHandle<()>>,
+    mut inputs: UnboundedReceiver<Input>,
+    mut cancels: Cancels,
+    events: broadcast::Sender<Event>,
+    answering: Arc<AtomicBool>,
+) {
+    if let Some(previous) = previous {
+        let _ = previous.await;
+    }
+    let mut history = match state.conversations.history(&conversation) {
+        Ok(history) => Some(history),
+        Err(e) => {
+            error!("Couldn't open conversation {}: {}", conversation.id, e);
+            None
+        }
+    };
+    info!("Opened room of conversation {}", conversation.id);
+
+    while let Some(input) = inputs.recv().await {
+        let (command, request, origin, number, done) = match input {
+            Input::Command {
+                command,
+                request,
+                origin,
+                number,
+                done,
+            } => (command, request, origin, number, done),
+            Input::Resync { origin } => {
+                if let Some(history) = &history {
+                    let reply = Reply {
+                        events: &events,
+                        origin,
+                        request: None,
+                    };
+                    reply.send(true, Some(history.nodes().len()), history_event(history));
+                }
+                continue;
+            }
+            Input::Close => {
+                let reply = Reply {
+                    events: &events,
+                    origin: NO_SOCKET,
+                    request: None,
+                };
+                reply.broadcast(ServerEvent::Error {
+                    code: ErrorCode::Failed,
+                    message: "The conversation was closed".to_string(),
+                    retry_after: None,
+                });
+                break;
+            }
+        };
+        let reply = Reply {
+            events: &events,
+            origin,
+            request: request.as_deref(),
+        };
+        let result = match &mut history {
+            Some(history) => {
+                let span = info_span!("command", socket = origin, request = request.as_deref());
+                process_command(
+                    command,
+                    number,
+                    history,
+                    &state.bot,
+                    &mut cancels,
+                    &answering,
+                    reply,
+                )
+                .instrument(span)
+                .await
+                .map(|mut outcome| {
+                    if done.is_some() {
+                        outcome.messages = html::history_messages(history);
+                    }
+                    outcome
+                })
+            }
+            None => Err(anyhow!("Couldn't open the conversation")),
+        };
+
+        if let Some(done) = done {
+            let _ = done.send(result);
+            continue;
+        }
+        let event = match result {
+            Ok(_) => ServerEvent::Ack,
+            Err(e) => {
+                warn!("Got error {} while processing request {:?}", e, request);
+                for cause in e.chain() {
+                    warn!("- cause {:?}", cause);
+                }
+                ServerEvent::Error {
+                    code: ErrorCode::Failed,
+                    message: e.to_string(),
+                    retry_after: None,
+                }
+            }
+        };
+        reply.private(event);
+    }
+    info!("Closed room of conversation {}", conversation.id);
+}
+
+/// Where the frames caused by a command go.
+#[derive(Clone, Copy)]
+struct Reply<'r> {
+    events: &'r broadcast::Sender<Event>,
+    origin: u64,
+    request: Option<&'r str>,
+}
+
+impl Reply<'_> {
+    /// Sends `event` to every socket of the room.
+    fn broadcast(self, event: ServerEvent) {
+        self.send(false, None, event);
+    }
+
+    /// Sends `event` to the socket of the command only.
+    fn private(self, event: ServerEvent) {
+        self.send(true, None, event);
+    }
+
+    fn send(self, private: bool, seq: Option<usize>, event: ServerEvent) {
+        let mut frame = ServerFrame::new(self.request.map(str::to_string), event);
+        frame.seq = seq;
+        let event = Event {
+            origin: self.origin,
+            private,
+            frame,
+        };
+        // A room without sockets is fine, e.g. for API requests
+        let _ = self.events.send(event);
+    }
+}
+
+async fn process_command<'a>(
+    command: ClientCommand,
+    number: u64,
+    history: &mut History<'a>,
+    bot: &'a Bot,
+    cancels: &mut Cancels,
+    answering: &AtomicBool,
+    reply: Reply<'_>,
+) -> Result<Outcome> {
+    match command {
+        ClientCommand::Message { text } => {
+            history.user(Message::user(&text)?);
+            send_last(history, reply);
+        }
+        ClientCommand::Edit { id, text } => {
+            history.edit(&id, &text).map_err(Rejected)?;
+            send_history(history, reply);
+        }
+        ClientCommand::Regenerate { id } => {
+            history.regenerate(&id).map_err(Rejected)?;
+            send_history(history, reply);
+        }
+        ClientCommand::Switch { id } => {
+            history.switch(&id).map_err(Rejected)?;
+            send_history(history, reply);
+            return Ok(Outcome::default());
+        }
+        ClientCommand::Feedback {
+            id,
+            rating,
+            comment,
+        } => {
+            let feedback = history.feedback(&id, rating, comment).map_err(Rejected)?;
+            return Ok(Outcome {
+                feedback: Some(feedback),
+                ..Outcome::default()
+            });
+        }
+        ClientCommand::Resume { seq } => {
+            for frame in replay(history, seq) {
+                reply.send(true, frame.seq, frame.event);
+            }
+            return Ok(Outcome::default());
+        }
+        // Handled by the sockets
+        ClientCommand::Cancel | ClientCommand::Settings(_) => return Ok(Outcome::default()),
+    }
+
+    answering.store(true, Ordering::Relaxed);
+    reply.broadcast(ServerEvent::Status {
+        state: State::Answering,
+    });
+    let answer = answer_or_cancel(bot, history, number, cancels, reply).await;
+    answering.store(false, Ordering::Relaxed);
+    reply.broadcast(ServerEvent::Status { state: State::Idle });
+    let answer = answer?;
+    let usage = answer.info.as_ref().map(|info| info.usage);
+    history.assistant(answer);
+    send_last(history, reply);
+    Ok(Outcome {
+        usage,
+        ..Outcome::default()
+    })
+}
+
+/// Answers the last question of `history`. A cancel stops the answer, which
+/// the bot still finishes with the part received so far so that its usage is
+/// recorded; a command cancelled before it starts isn't answered at all.
+async fn answer_or_cancel<'a>(
+    bot: &'a Bot,
+    history: &History<'a>,
+    number: u64,
+    cancels: &mut Cancels,
+    reply: Reply<'_>,
+) -> Result<Message<'a>> {
+    if cancels.cancelled(number) {
+        info!("Answer cancelled before it started");
+        return Ok(Message::cancelled(""));
+    }
+    let (deltas, mut received) = mpsc::unbounded_channel();
+    let answer = bot.answer_streaming(history, Some(&deltas));
+    tokio::pin!(answer);
+    let mut partial = String::new();
+    let mut stopped = false;
+    loop {
+        tokio::select! {
+            answer = &mut answer => {
+                let mut answer = answer?;
+                answer.cancelled = stopped;
+                return Ok(answer);
+            }
+            Some(delta) = received.recv() => {
+                partial.push_str(&delta);
+                reply.broadcast(ServerEvent::Delta { text: delta });
+            }
+            () = cancels.wait(number), if !stopped => {
+                info!("Answer cancelled after {} characters", partial.len());
+                // The deltas already sent are still broadcast
+                received.close();
+                stopped = true;
+            }
+        }
+    }
+}
+
+/// Receives the cancels of a room, each stopping the commands up to the
+/// number it carries.
+struct Cancels {
+    receiver: UnboundedReceiver<u64>,
+    /// Commands up to this one are cancelled.
+    through: u64,
+}
+
+impl Cancels {
+    fn new(receiver: UnboundedReceiver<u64>) -> Self {
+        Self {
+            receiver,
+            through: 0,
+        }
+    }
+
+    fn cancelled(&mut self, command: u64) -> bool {
+        while let Ok(number) = self.receiver.try_recv() {
+            self.through = self.through.max(number);
+        }
+        command <= self.through
+    }
+
+    /// Waits until `command` is cancelled.
+    async fn wait(&mut self, command: u64) {
+        while self.through < command {
+            match self.receiver.recv().await {
+                Some(number) => self.through = self.through.max(number),
+                // The room is closed, but the answer is still stored
+                None => std::future::pending().await,
+            }
+        }
+    }
+}
+
+fn send_last(history: &History<'_>, reply: Reply<'_>) {
+    if let Some(last) = history.last() {
+        let event = ServerEvent::message(HTMLMsg::in_history(history, last));
+        reply.send(false, Some(history.nodes().len()), event);
+    }
+}
+
+/// Replaces the messages shown by the clients with the active branch.
+fn send_history(history: &History<'_>, reply: Reply<'_>) {
+    reply.send(false, Some(history.nodes().len()), history_event(history));
+}
+
+fn history_event(history: &History<'_>) -> ServerEvent {
+    ServerEvent::History {
+        messages: html::history_messages(history),
+    }
+}
+
+/// The frames bringing a client which saw the messages up to `seq` up to
+/// date: the messages added since if they continue the active branch, the
+/// whole active branch otherwise.
+fn replay(history: &History<'_>, seq: usize) -> Vec<ServerFrame> {
+    let nodes = history.nodes();
+    if seq == nodes.len() {
+        return Vec::new();
+    }
+    // Messages are only ever added, to the end of `nodes`
+    let branch = history.messages();
+    let missed = nodes.get(seq..).unwrap_or_default();
+    let continues = match branch.len().checked_sub(missed.len()) {
+        Some(start) if !missed.is_empty() => {
+            let seen = match start.checked_sub(1) {
+                Some(previous) => seq > 0 && branch[previous].id == nodes[seq - 1].id,
+                None => seq == 0,
+            };
+            seen && branch[start..]
+                .iter()
+                .zip(missed)
+                .all(|(message, node)| message.id == node.id)
+        }
+        _ => false,
+    };
+    if !continues {
+        return vec![ServerFrame::new(None, history_event(history)).with_seq(nodes.len())];
+    }
+    missed
+        .iter()
+        .enumerate()
+        .map(|(i, node)| {
+            let event = ServerEvent::message(HTMLMsg::in_history(history, node));
+            ServerFrame::new(None, event).with_seq(seq + i + 1)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use async_openai::types::Role;
+
+    use super::*;
+
+    fn history() -> History<'static> {
+        let mut history = History::in_memory();
+        for text in ["q1", "a1", "q2", "a2"] {
+            let role = if text.starts_with('q') {
+                Role::User
+            } else {
+                Role::Assistant
+            };
+            history.push(Message::new(role, text));
+        }
+        history
+    }
+
+    /// The content and seq of the message frames, or `None` for a history.
+    fn frames(frames: Vec<ServerFrame>) -> Vec<(Option<String>, Option<usize>)> {
+        frames
+            .into_iter()
+            .map(|frame| match frame.event {
+                ServerEvent::User { message } | ServerEvent::Assistant { message } => {
+                    (Some(message.content), frame.seq)
+                }
+                ServerEvent::History { .. } => (None, frame.seq),
+                event => panic!("unexpected {:?}", event),
+            })
+            .collect()
+    }
+
+    /// A room of conversation `id` whose writer only waits to be closed.
+    fn open(hub: &Hub, id: &str) -> Arc<Room> {
+        let (inputs, mut input_rx) = mpsc::unbounded_channel();
+        let (cancel, _) = mpsc::unbounded_channel();
+        let room = Arc::new(Room {
+            inputs,
+            queued: AtomicU64::new(0),
+            cancel,
+            events: broadcast::channel(EVENTS).0,
+            answering: Arc::new(AtomicBool::new(false)),
+        });
+        let writer = tokio::spawn(async move {
+            while let Some(input) = input_rx.recv().await {
+                if let Input::Close = input {
+                    break;
+                }
+            }
+        });
+        let entry = Entry {
+            room: Arc::downgrade(&room),
+            writer,
+        };
+        hub.rooms.lock().unwrap().insert(id.to_string(), entry);
+        room
+    }
+
+    #[tokio::test]
+    async fn closed_rooms_refuse_commands() {
+        let hub = Hub::default();
+        let room = open(&hub, "c1");
+        assert_eq!(hub.unless_open("c1", || "deleted"), None);
+        assert_eq!(hub.unless_open("c2", || "deleted"), Some("deleted"));
+
+        hub.close("c1").await;
+        assert!(room.resync(1).is_err());
+        assert_eq!(hub.open_rooms(), 0);
+        assert_eq!(hub.unless_open("c1", || "deleted"), Some("deleted"));
+    }
+
+    #[test]
+    fn replay_sends_the_missed_messages() {
+        let history = history();
+        assert!(replay(&history, 4).is_empty());
+        assert_eq!(
+            frames(replay(&history, 2)),
+            [
+                (Some("q2".to_string()), Some(3)),
+                (Some("a2".to_string()), Some(4))
+            ]
+        );
+        assert_eq!(frames(replay(&history, 0)).len(), 4);
+    }
+
+    #[test]
+    fn replay_sends_the_history_after_a_branch_change() {
+        let mut history = history();
+        let q2 = history.messages()[2].id.clone();
+        history.edit(&q2, "q2'").unwrap();
+        // The client saw a2, which isn't on the active branch anymore
+        assert_eq!(frames(replay(&history, 4)), [(None, Some(5))]);
+
+        // A new answer replaces the one the client saw
+        let mut history = self::history();
+        let a1 = history.messages()[1].id.clone();
+        history.regenerate(&a1).unwrap();
+        history.assistant(Message::new(Role::Assistant, "a1'"));
+        assert_eq!(frames(replay(&history, 4)), [(None, Some(5))]);
+    }
+
+    #[test]
+    fn replay_sends_the_history_to_unknown_clients() {
+        let history = history();
+        assert_eq!(frames(replay(&history, 9)), [(None, Some(4))]);
+    }
+
+    #[test]
+    fn cancels_stop_the_commands_queued_before() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut cancels = Cancels::new(receiver);
+        assert!(!cancels.cancelled(1));
+        sender.send(2).unwrap();
+        sender.send(1).unwrap();
+        assert!(cancels.cancelled(1));
+        assert!(cancels.cancelled(2));
+        assert!(!cancels.cancelled(3));
+    }
+}
@@ -0,0 +1,109 @@
+//! A JSON file shared by the server and the `gpt-rs` commands changing it,
+//! such as the accounts of `gpt-rs user`.
+//!
+//! The content is read again whenever the file changed on disk, so a running
+//! server sees the changes of the commands, and every change is made to the
+//! latest content. Changes are written to a temporary file first so a crash
+//! never truncates the file.
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{info, warn};
+
+/// Modification time and size of the file as last read or written.
+type Stamp = Option<(SystemTime, u64)>;
+
+pub struct JsonFile<T> {
+    path: PathBuf,
+    /// What the content is, for messages.
+    what: &'static str,
+    content: Mutex<(T, Stamp)>,
+}
+
+/// The locked content of a `JsonFile`.
+pub struct Locked<'a, T> {
+    file: &'a JsonFile<T>,
+    guard: MutexGuard<'a, (T, Stamp)>,
+}
+
+impl<T: Default + Serialize + DeserializeOwned> JsonFile<T> {
+    /// Loads `path`, which is created on the first change.
+    pub fn open(path: &Path, what: &'static str) -> Result<Self> {
+        let stamp = stamp(path);
+        let content = match stamp {
+            Some(_) => read(path)
+                .with_context(|| format!("Couldn't read {} from {}", what, path.display()))?,
+            None => T::default(),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            what,
+            content: Mutex::new((content, stamp)),
+        })
+    }
+
+    /// Locks the content, read again first if the file changed. A file which
+    /// can't be read is reported and the content last read is kept.
+    pub fn lock(&self) -> Locked<'_, T> {
+        let mut guard = self.content.lock().unwrap_or_else(|e| e.into_inner());
+        let stamp = stamp(&self.path);
+        if stamp.is_some() && stamp != guard.1 {
+            match read(&self.path) {
+                Ok(content) => {
+                    info!("Reloaded {} from {}", self.what, self.path.display());
+                    *guard = (content, stamp);
+                }
+                Err(e) => warn!(
+                    "Couldn't read {} from {}: {:#}",
+                    self.what,
+                    self.path.display(),
+                    e
+                ),
+            }
+        }
+        Locked { file: self, guard }
+    }
+}
+
+impl<T: Serialize> Locked<'_, T> {
+    /// Writes the content to the file.
+    pub fn save(&mut self) -> Result<()> {
+        let path = &self.file.path;
+        let tmp = path.with_extension("tmp");
+        serde_json::to_writer_pretty(File::create(&tmp)?, &self.guard.0)?;
+        fs::rename(&tmp, path)?;
+        self.guard.1 = stamp(path);
+        Ok(())
+    }
+}
+
+impl<T> Deref for Locked<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard.0
+    }
+}
+
+impl<T> DerefMut for Locked<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard.0
+    }
+}
+
+fn stamp(path: &Path) -> Stamp {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+fn read<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+}
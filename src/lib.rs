@@ -1,27 +1,49 @@
+pub mod api;
+pub mod api_keys;
+pub mod auth;
+pub mod bot;
+pub mod cassette;
+pub mod citations;
+pub mod completions;
+pub mod config;
+pub mod conversations;
 pub mod embeddings;
+pub mod eval;
+pub mod export;
+pub mod feedback;
+pub mod health;
 pub mod history;
 pub mod html;
+pub mod hub;
+pub mod json_file;
+pub mod metrics;
 pub mod openai;
+pub mod rate_limit;
+pub mod retention;
+pub mod session;
+pub mod store;
+pub mod trace;
+pub mod usage;
+pub mod users;
 pub mod websocket;
 pub mod cli;
 
-pub const EMBEDDING_SIZE: usize = 1536;
-pub const CHAT_MODEL: &str = "gpt-3.5-turbo";
-pub const EMBEDDING_MODEL: &str = "text-embedding-ada-002";
-pub const DATA_DIR: &str = "./data";
-pub const HISTORY_DIR: &str = "./history";
-
-pub const MAX_TOKENS: u16 = 4096;
-pub const MAX_HISTORY: u16 = 1024;
-pub const RESPONSE_SIZE: u16 = 512;
-
+/// Evaluates `$expr`, recording its duration in the histogram of stage
+/// `$label` and in the timings of `$trace`, if given.
 #[macro_export]
 macro_rules! timer {
     ($label:expr, $expr:expr) => {{
         let start = std::time::Instant::now();
         let result = $expr;
         let duration = start.elapsed();
-        info!("{}: time taken: {:?}", $label, duration);
+        tracing::info!(stage = $label, ?duration, "stage done");
+        $crate::metrics::stage($label, duration);
+        result
+    }};
+    ($label:expr, $trace:expr, $expr:expr) => {{
+        let start = std::time::Instant::now();
+        let result = $crate::timer!($label, $expr);
+        $trace.timing($label, start.elapsed());
         result
     }};
 }
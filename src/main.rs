@@ -1,229 +1,746 @@
-use anyhow::Result;
-use async_openai::types::ChatCompletionRequestMessage;
-use axum::extract::State;
-use axum::response::Redirect;
+use anyhow::{anyhow, Result};
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::{header, HeaderMap};
+use axum::response::{Redirect, Response};
 use axum::routing::post;
-use gpt_rs::history::{History, InfoBuilder, Message};
-use gpt_rs::websocket::WebSocket;
+use axum::Json;
+use gpt_rs::api::{self, ApiError, AppState};
+use gpt_rs::api_keys::{self, ApiKeys, Quotas};
+use gpt_rs::auth;
+use gpt_rs::bot::Bot;
+use gpt_rs::completions;
+use gpt_rs::history::Rating;
+use gpt_rs::websocket::{
+    ClientCommand, ClientFrame, ErrorCode, ServerEvent, ServerFrame, Settings, State as WsState,
+    WebSocket,
+};
 use gpt_rs::cli::cli_chat_loop;
-use gpt_rs::{DATA_DIR, MAX_TOKENS, RESPONSE_SIZE};
-use gpt_rs::timer;
+use gpt_rs::config::Config;
+use gpt_rs::conversations::{Conversation, Conversations};
+use gpt_rs::session;
+use gpt_rs::eval;
+use gpt_rs::export;
+use gpt_rs::feedback;
+use gpt_rs::rate_limit::{self, RateLimiter};
+use gpt_rs::retention;
+use gpt_rs::store::{self, JsonlStore, SqliteStore};
+use gpt_rs::users::Users;
 use std::fs::File;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use structopt::StructOpt;
-//use tracing::{info,error,warn};
+use tracing::{error, field, info, info_span, warn, Instrument, Level, Span};
+use tracing_subscriber::EnvFilter;
 
 use tokio::signal;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Duration;
 
 
 
 use axum::{response::IntoResponse, routing::get, Router};
 
 use tower_http::services::ServeDir;
-
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tower_http::trace::{DefaultMakeSpan, TraceLayer};
 
 use axum::extract::ws::{WebSocket as AxumWebSocket, WebSocketUpgrade};
 
 //allows to split the websocket stream into separate TX and RX branches
 //use futures::{sink::SinkExt, stream::StreamExt};
-use axum_sessions::{extractors::WritableSession, SessionLayer};
+use axum_sessions::{extractors::WritableSession, SameSite, SessionLayer};
 
 use gpt_rs::embeddings::Embeddings;
-use gpt_rs::html::{HtmlTemplate, IndexTemplate, Message as HTMLMsg};
+use gpt_rs::health::{self, Health};
+use gpt_rs::html::{self, HtmlTemplate, IndexTemplate};
+use gpt_rs::hub::Hub;
 use gpt_rs::openai::Client;
-use tracing_subscriber::fmt::format::FmtSpan;
-
-use std::println as info;
-use std::println as error;
-use std::println as warn;
-
-pub struct AppState {
-    embeddings: Embeddings,
-    client: Client,
-}
+use gpt_rs::trace::TraceLog;
+use gpt_rs::usage::Ledger;
 
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "gpt-rs", about = "AI chatbot webapp")]
 struct Opt {
-    #[structopt(short = "l", long = "listen", default_value = "0.0.0.0:5000")]
-    listen: String,
+    /// Config file, `./gpt-rs.toml` is used if it exists
+    #[structopt(long = "config", env = "GPT_RS_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Override a config value, e.g. `-o budget.max_tokens=8192`
+    #[structopt(short = "o", long = "set", number_of_values = 1)]
+    overrides: Vec<String>,
+
+    #[structopt(short = "l", long = "listen")]
+    listen: Option<String>,
 
     #[structopt(short = "c", long = "cli")]
     cli: bool,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Measure retrieval quality on a JSONL file of questions with expected articles
+    Eval {
+        /// JSONL file with {"id", "question", "expected": [filenames]} per line
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+
+        /// Values of k for recall@k
+        #[structopt(short = "k", long = "k", default_value = "1,3,5,10", use_delimiter = true)]
+        ks: Vec<usize>,
+
+        /// Also answer every question with the chat model
+        #[structopt(long = "answer")]
+        answer: bool,
+
+        /// Write the full report as JSON
+        #[structopt(short = "O", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Import the JSONL history files into the SQLite database
+    MigrateHistory {
+        /// Directory of the history files, `paths.history_dir` by default
+        #[structopt(long = "from", parse(from_os_str))]
+        from: Option<PathBuf>,
+
+        /// Database to import into, `storage.database` by default
+        #[structopt(long = "to", parse(from_os_str))]
+        to: Option<PathBuf>,
+    },
+    /// Write a conversation as Markdown, JSON or HTML
+    Export {
+        /// Id of the conversation
+        id: String,
+
+        /// markdown, json or html
+        #[structopt(short = "f", long = "format", default_value = "markdown")]
+        format: export::Format,
+
+        /// Output file, standard output by default
+        #[structopt(short = "O", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        /// URL of the server, e.g. https://chat.example.com, to link the
+        /// cited articles to; they aren't linked by default
+        #[structopt(long = "base-url")]
+        base_url: Option<String>,
+    },
+    /// Write the rated answers as a JSONL dataset
+    FeedbackDataset {
+        /// Only answers rated up or down
+        #[structopt(long = "rating")]
+        rating: Option<Rating>,
+
+        /// Output file, standard output by default
+        #[structopt(short = "O", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Manage the accounts used with `auth.enabled`
+    User(UserCommand),
+    /// Manage the keys of /api and /v1
+    ApiKey(ApiKeyCommand),
+    /// Apply the retention policy once
+    Retention {
+        /// Only list what would be removed
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum UserCommand {
+    /// Create an account, asking for its password
+    Add {
+        username: String,
+
+        /// Read the password from the first line of standard input
+        #[structopt(long = "password-stdin")]
+        password_stdin: bool,
+    },
+    /// Change the password of an account
+    Passwd {
+        username: String,
+
+        /// Read the password from the first line of standard input
+        #[structopt(long = "password-stdin")]
+        password_stdin: bool,
+    },
+    /// Remove an account; its conversations are kept
+    Remove { username: String },
+    /// List the accounts
+    List,
+}
+
+#[derive(Debug, StructOpt)]
+enum ApiKeyCommand {
+    /// Create a key and print its token
+    Create {
+        /// What the key is used for
+        name: String,
+
+        /// Account whose conversations the key accesses, a user of its own by default
+        #[structopt(long = "user")]
+        user: Option<String>,
+
+        #[structopt(long = "requests-per-day")]
+        requests_per_day: Option<u64>,
+
+        /// Prompt, completion and embedding tokens; the request reaching the
+        /// quota is answered in full, the next ones are refused
+        #[structopt(long = "tokens-per-day")]
+        tokens_per_day: Option<u64>,
+    },
+    /// Revoke a key; requests with it are refused from then on
+    Revoke { id: String },
+    /// List the keys with their usage
+    List,
 }
 
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let opt = Opt::from_args();
-    std::env::set_var("RUST_LOG", "info");
+    // Standard output is for the CLI and the exports
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
 
 
     info!("gpt-rs starting up...");
 
-    let store = async_session::CookieStore::new();
-    let secret = b"593jfdslgdsgdssjgdsghljfshp[jmvadlk;hgadljgdahm'dvahfdlfgadssmlf"; // MUST be at least 64 bytes!
-    let session_layer = SessionLayer::new(store, secret);
+    let mut overrides = opt.overrides.clone();
+    if let Some(listen) = &opt.listen {
+        overrides.push(format!("server.listen={}", listen));
+    }
+    let config = Config::load(opt.config.as_deref(), &overrides)?;
 
-    let api_key =
-        std::env::var("OPENAI_API_KEY").expect("Expect OPENAI_API_KEY environment variable");
-    let file = File::open("./embeddings.csv").unwrap();
-    let reader = std::io::BufReader::new(file);
-    let embeddings = Embeddings::load(reader)?;
-    info!("Loaded embeddings");
+    if let Some(cmd) = opt.cmd {
+        return run_command(cmd, config).await;
+    }
 
-    let client = Client::new(&api_key);
+    let bot = open_bot(config)?;
+    let history_store = store::open(&bot.config)?;
+    let conversations = Conversations::new(history_store.clone());
 
     if opt.cli {
-        cli_chat_loop(&embeddings, &client).await;
-        return Ok(())
+        return cli_chat_loop(&bot, &conversations).await;
+    }
+
+    let cookie_store = async_session::CookieStore::new();
+    if bot.config.server.session_secret.is_none() {
+        warn!("server.session_secret is not set, sessions won't survive a restart");
     }
+    let mut session_layer = SessionLayer::new(cookie_store, &bot.config.session_secret());
+    if bot.config.auth.oidc.is_some() {
+        // The session must come back with the redirect from the provider
+        session_layer = session_layer.with_same_site_policy(SameSite::Lax);
+    }
+
+    let users = Users::open(&bot.config.paths.users)?;
+    let paths = &bot.config.paths;
+    let api_keys = ApiKeys::open(&paths.api_keys, &paths.api_key_usage)?;
+    let rate_limiter = RateLimiter::new(bot.config.rate_limit.clone());
 
-    info!("\x1b[0;32mlistening on {} \x1b[0m", opt.listen);
+    info!("\x1b[0;32mlistening on {} \x1b[0m", bot.config.server.listen);
 
-    let app_state = Arc::new(AppState { embeddings, client });
+    let listen = bot.config.server.listen.parse()?;
+    let data_dir = bot.config.paths.data_dir.clone();
+    if bot.config.auth.enabled && users.list().is_empty() && bot.config.auth.oidc.is_none() {
+        warn!("auth is enabled but there are no users yet, add one with `gpt-rs user add`");
+    }
+    let app_state = Arc::new(AppState {
+        bot,
+        conversations,
+        users,
+        api_keys,
+        rate_limiter,
+        hub: Hub::default(),
+        health: Health::default(),
+    });
+    if app_state.bot.config.retention.enabled {
+        retention::spawn(app_state.clone(), history_store);
+    }
+    let limit = axum::middleware::from_fn_with_state(app_state.clone(), rate_limit::limit);
+    let authenticate =
+        axum::middleware::from_fn_with_state(app_state.clone(), api_keys::authenticate);
+    let require_user = axum::middleware::from_fn_with_state(app_state.clone(), auth::require_user);
     let app = Router::new()
         .route("/", get(index))
-        .route("/clear_history", post(clear_history))
+        .route("/conversations/new", post(new_conversation))
+        .route("/conversations/:id", get(open_conversation))
         .route("/websocket", get(websocket_handler))
-        .nest_service("/context", ServeDir::new(DATA_DIR))
+        .route("/admin/usage", get(usage_summary))
+        .route("/metrics", get(metrics))
+        .merge(health::router())
+        .merge(auth::router().route_layer(limit.clone()))
+        .nest(
+            "/api",
+            api::router()
+                .route_layer(limit.clone())
+                .route_layer(authenticate.clone()),
+        )
+        .nest(
+            "/v1",
+            completions::router()
+                .route_layer(limit)
+                .route_layer(authenticate),
+        )
+        .merge(
+            Router::new()
+                .nest_service("/context", ServeDir::new(data_dir))
+                .route_layer(require_user),
+        )
         .layer(session_layer)
-        .with_state(app_state);
+        .layer(TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::new().level(Level::INFO)))
+        .with_state(app_state.clone());
+    api_keys::spawn_flush(app_state.clone());
 
-    axum::Server::bind(&opt.listen.parse().unwrap())
-        .serve(app.into_make_service())
+    axum::Server::bind(&listen)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap();
+    app_state.api_keys.flush()?;
+
+    Ok(())
+}
 
+/// Runs `cmd`, opening only what it needs: managing accounts and keys works
+/// without the articles and the OpenAI API key.
+async fn run_command(cmd: Command, config: Config) -> Result<()> {
+    match cmd {
+        Command::Eval {
+            input,
+            ks,
+            answer,
+            output,
+        } => {
+            let cases = eval::load_cases(&input)?;
+            let bot = open_bot(config)?;
+            let report = eval::evaluate(&bot, &cases, &ks, answer).await;
+            let summary = &report.summary;
+            println!("questions: {} (errors: {})", summary.questions, summary.errors);
+            for (k, recall) in &summary.recall {
+                println!("recall@{}: {:.3}", k, recall);
+            }
+            println!("MRR: {:.3}", summary.mrr);
+            println!("context recall: {:.3}", summary.context_recall);
+            if let Some(cited) = summary.cited_expected {
+                println!("answers citing an expected article: {:.3}", cited);
+            }
+            println!(
+                "usage: {} tokens, ${:.5}",
+                summary.usage.total(),
+                summary.cost
+            );
+            for id in &summary.failures {
+                let case = report.questions.iter().find(|q| &q.id == id).unwrap();
+                match &case.error {
+                    Some(error) => println!("FAILED {}: {} ({})", id, case.question, error),
+                    None => println!("MISSED {}: {}", id, case.question),
+                }
+            }
+            if let Some(output) = output {
+                serde_json::to_writer_pretty(File::create(&output)?, &report)?;
+                println!("report written to {}", output.display());
+            }
+        }
+        Command::MigrateHistory { from, to } => {
+            let from = from.unwrap_or_else(|| config.paths.history_dir.clone());
+            let to = to.unwrap_or_else(|| config.storage.database.clone());
+            let migration = store::migrate(&JsonlStore::new(&from), &SqliteStore::open(&to)?)?;
+            println!(
+                "imported {} conversations ({} messages) into {}, skipped {} already present",
+                migration.conversations,
+                migration.messages,
+                to.display(),
+                migration.skipped
+            );
+        }
+        Command::Export {
+            id,
+            format,
+            output,
+            base_url,
+        } => {
+            let conversations = Conversations::new(store::open(&config)?);
+            let conversation = conversations
+                .get(&id)?
+                .ok_or_else(|| anyhow!("No conversation {}", id))?;
+            let history = conversations.history(&conversation)?;
+            let base = base_url.as_deref().map(|url| url.trim_end_matches('/'));
+            let exported = export::export(&conversation, &history, format, base)?;
+            match output {
+                Some(output) => {
+                    std::fs::write(&output, exported)?;
+                    println!("conversation written to {}", output.display());
+                }
+                None => print!("{}", exported),
+            }
+        }
+        Command::FeedbackDataset { rating, output } => {
+            let records = feedback::dataset(store::open(&config)?, rating)?;
+            let mut lines = String::new();
+            for record in &records {
+                lines.push_str(&serde_json::to_string(record)?);
+                lines.push('\n');
+            }
+            match output {
+                Some(output) => {
+                    std::fs::write(&output, lines)?;
+                    println!(
+                        "{} rated answers written to {}",
+                        records.len(),
+                        output.display()
+                    );
+                }
+                None => print!("{}", lines),
+            }
+        }
+        Command::User(UserCommand::Add {
+            username,
+            password_stdin,
+        }) => {
+            let users = Users::open(&config.paths.users)?;
+            let user = users.add(&username, &read_password(password_stdin)?)?;
+            println!("added {} ({})", user.username, user.id);
+        }
+        Command::User(UserCommand::Passwd {
+            username,
+            password_stdin,
+        }) => {
+            let users = Users::open(&config.paths.users)?;
+            users.set_password(&username, &read_password(password_stdin)?)?;
+            println!("password of {} changed", username);
+        }
+        Command::User(UserCommand::Remove { username }) => {
+            let user = Users::open(&config.paths.users)?.remove(&username)?;
+            println!("removed {} ({})", user.username, user.id);
+        }
+        Command::User(UserCommand::List) => {
+            for user in Users::open(&config.paths.users)?.list() {
+                let login = match &user.oidc {
+                    Some(oidc) => format!("oidc {}", oidc.issuer),
+                    None => "password".to_string(),
+                };
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    user.username, user.id, login, user.created
+                );
+            }
+        }
+        Command::ApiKey(ApiKeyCommand::Create {
+            name,
+            user,
+            requests_per_day,
+            tokens_per_day,
+        }) => {
+            let owner = match user {
+                Some(username) => {
+                    Users::open(&config.paths.users)?
+                        .by_username(&username)
+                        .ok_or_else(|| anyhow!("No user {}", username))?
+                        .id
+                }
+                None => session::random_id(),
+            };
+            let quotas = Quotas {
+                requests_per_day,
+                tokens_per_day,
+            };
+            let api_keys = ApiKeys::open(&config.paths.api_keys, &config.paths.api_key_usage)?;
+            let (key, token) = api_keys.create(&name, &owner, quotas)?;
+            println!("created API key {} for {}", key.id, key.owner);
+            println!("{}", token);
+        }
+        Command::ApiKey(ApiKeyCommand::Revoke { id }) => {
+            let api_keys = ApiKeys::open(&config.paths.api_keys, &config.paths.api_key_usage)?;
+            let key = api_keys.revoke(&id)?;
+            println!("revoked {} ({})", key.id, key.name);
+        }
+        Command::ApiKey(ApiKeyCommand::List) => {
+            let api_keys = ApiKeys::open(&config.paths.api_keys, &config.paths.api_key_usage)?;
+            let limit = |quota: Option<u64>| quota.map_or("-".to_string(), |q| q.to_string());
+            for key in api_keys.list() {
+                let usage = api_keys.usage(&key.id);
+                let status = match key.revoked {
+                    Some(revoked) => format!("revoked {}", revoked),
+                    None => "active".to_string(),
+                };
+                println!(
+                    "{}\t{}\t{}\t{}\ttoday ({}): {}/{} requests, {}/{} tokens\ttotal: {} requests, {} tokens",
+                    key.id,
+                    key.name,
+                    key.owner,
+                    status,
+                    usage.day,
+                    usage.requests,
+                    limit(key.requests_per_day),
+                    usage.tokens,
+                    limit(key.tokens_per_day),
+                    usage.total_requests,
+                    usage.total_tokens
+                );
+            }
+        }
+        Command::Retention { dry_run } => {
+            let store = store::open(&config)?;
+            let policy = &config.retention;
+            retention::enforce(store.as_ref(), policy, dry_run || policy.dry_run, None)?;
+        }
+    }
     Ok(())
 }
 
+/// Loads the articles and sets up the OpenAI client for answering.
+fn open_bot(config: Config) -> Result<Bot> {
+    config.validate_bot()?;
+    let file = File::open(&config.paths.embeddings)?;
+    let reader = std::io::BufReader::new(file);
+    let embeddings = Embeddings::load(
+        reader,
+        config.openai.embedding_size,
+        config.paths.data_dir.clone(),
+    )?;
+    info!("Loaded embeddings");
+
+    let client = Client::new(&config.openai)?;
+    let ledger = Ledger::open(&config.paths.usage_log)?;
+    let traces = TraceLog::open(&config.trace)?;
+
+    Ok(Bot {
+        embeddings,
+        client,
+        config,
+        ledger,
+        traces,
+    })
+}
+
+fn read_password(stdin: bool) -> Result<String> {
+    if stdin {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password)?;
+        return Ok(password.trim_end_matches(['\r', '\n']).to_string());
+    }
+    let password = rpassword::prompt_password("Password: ")?;
+    if rpassword::prompt_password("Repeat password: ")? != password {
+        return Err(anyhow!("Passwords don't match"));
+    }
+    Ok(password)
+}
+
 async fn websocket_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
-    session: WritableSession,
-) -> impl IntoResponse {
-    let history = session
-        .get::<String>("hist")
-        .and_then(|filename| {
-            History::load(&filename)
-                .map_err(|e| error!("Couldn't open file {}: {}", filename, e))
-                .ok()
-        })
-        .unwrap_or_else(History::new);
-
-    // if let Some(name) = &history.name {
-    //     session.insert_raw("hist", name.to_string());
-    // }
-
-    ws.on_upgrade(|socket| websocket(socket, state, history))
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    mut session: WritableSession,
+) -> Result<impl IntoResponse, ApiError> {
+    let user = api::user(&state, &mut session)?;
+    let conversation = session::current_conversation(&state.conversations, &mut session, &user)?;
+    let ip = state.rate_limiter.client_ip(&headers, peer);
+    let span = info_span!("websocket", %user, %ip, socket = field::Empty);
+    Ok(ws.on_upgrade(move |socket| {
+        websocket(socket, state, conversation, user, ip).instrument(span)
+    }))
 }
 
-async fn websocket(socket: AxumWebSocket, state: Arc<AppState>, mut history: History<'_>) {
+async fn websocket(
+    socket: AxumWebSocket,
+    state: Arc<AppState>,
+    conversation: Conversation,
+    user: String,
+    ip: IpAddr,
+) {
     //send a ping (unsupported by some browsers) just to kick things off and get a response
     //
 
     info!("\x1b[0;32mopen socket2 \x1b[0m");
-    match WebSocket::initiate(socket).await {
+    let heartbeat = Duration::from_secs(state.bot.config.server.heartbeat_seconds);
+    let idle_timeout = Duration::from_secs(state.bot.config.server.idle_timeout_seconds);
+    let mut socket = match WebSocket::initiate(socket, heartbeat, idle_timeout).await {
+        Ok(socket) => socket,
         Err(e) => {
             error!("Couldn't initiate websocket {}", e);
+            return;
         }
-        Ok(mut socket) => {
-            while let Some(msg) = socket.next().await {
-                info!("Got message: {}", msg);
-                if let Err(e) = process_message(
-                    &msg,
-                    &mut history,
-                    &state.embeddings,
-                    &state.client,
-                    &mut socket,
-                )
-                .await
-                {
-                    warn!("Got error {} while processing message {}", e, msg);
-                    for cause in e.chain() {
-                        warn!("- cause {:?}", cause);
+    };
+
+    // The room applies the commands of every socket on the conversation and
+    // sends all of them what happens, so the socket can read a cancel while
+    // an answer is written
+    let room = state.hub.attach(&state, &conversation);
+    let id = state.hub.socket_id();
+    Span::current().record("socket", id);
+    let mut events = room.subscribe();
+    let mut settings = Settings::default();
+
+    loop {
+        tokio::select! {
+            msg = socket.next() => {
+                let Some(msg) = msg else {
+                    break;
+                };
+                let frame = match ClientFrame::parse(&msg) {
+                    Ok(frame) => frame,
+                    Err(error) => {
+                        warn!("Invalid frame of {} bytes", msg.len());
+                        if socket.send(*error).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                // Not the text, which belongs to the user
+                info!("Got {} frame of {} bytes", frame.command.name(), msg.len());
+                let request = frame.request;
+                let response = match frame.command {
+                    ClientCommand::Cancel => {
+                        room.cancel();
+                        ServerEvent::Ack
                     }
+                    ClientCommand::Settings(new) => {
+                        settings = new;
+                        ServerEvent::Ack
+                    }
+                    // Replayed by the room, after the command being processed
+                    ClientCommand::Resume { seq } => {
+                        if room.answering() {
+                            let answering = ServerEvent::Status { state: WsState::Answering };
+                            if socket.send(ServerFrame::new(None, answering)).await.is_err() {
+                                break;
+                            }
+                        }
+                        if room.send(id, request, ClientCommand::Resume { seq }).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    command => match state.rate_limiter.check(Some(&user), Some(ip)) {
+                        Err(limited) => {
+                            warn!("Rate limited {} ({}): {}", user, ip, limited);
+                            ServerEvent::Error {
+                                code: ErrorCode::RateLimited,
+                                message: limited.to_string(),
+                                retry_after: Some(limited.retry_after_secs()),
+                            }
+                        }
+                        Ok(()) => {
+                            if let ClientCommand::Message { text } | ClientCommand::Edit { text, .. } = &command {
+                                if let Err(e) = state.conversations.touch(&conversation.id, text) {
+                                    error!("Couldn't update conversation {}: {}", conversation.id, e);
+                                }
+                            }
+                            if room.send(id, request, command).is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                    },
+                };
+                if let Err(e) = socket.send(ServerFrame::new(request, response)).await {
+                    warn!("Couldn't send message: {}", e);
+                    break;
+                }
+            }
+            event = events.recv() => {
+                let frame = match event {
+                    Ok(event) => event.for_socket(id),
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Socket {} missed {} frames", id, missed);
+                        let _ = room.resync(id);
+                        None
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let Some(frame) = frame else {
+                    continue;
+                };
+                if !settings.stream && matches!(frame.event, ServerEvent::Delta { .. }) {
+                    continue;
+                }
+                if let Err(e) = socket.send(frame).await {
+                    warn!("Couldn't send message: {}", e);
+                    break;
                 }
             }
         }
     }
+    state.hub.close_socket();
+    // A command of this socket being processed is still finished and stored
 }
 
-async fn process_message<'a, 'b>(
-    msg: &str,
-    history: &mut History<'b>,
-    embeddings: &'a Embeddings,
-    client: &Client,
-    socket: &mut WebSocket,
-) -> Result<()>
-where
-    'a: 'b,
-{
-    let mut info = InfoBuilder::default();
-    let user_msg = Message::user(msg)?;
-    info.user_message_tokens(user_msg.tokens.into());
-    socket.send(HTMLMsg::from(&user_msg)).await?;
-
-    history.user(user_msg.clone());
-
-    let pruned_messages = history.prune_history();
-    info.history_count(pruned_messages.len());
-
-    let history_size = pruned_messages.iter().map(|m| m.tokens).sum::<u16>();
-    info.history_size(history_size.into());
-
-    let emb = timer!("get_embedding", {
-        client.get_embedding(msg).await?
-    });
-    let (context_msg, context_info) = timer!("prepare_context", {
-        embeddings.prepare_context(&emb, MAX_TOKENS - history_size - RESPONSE_SIZE)?
-    });
-
-    info.context_info(context_info);
-
-    let mut messages = vec![context_msg];
-    messages.extend_from_slice(
-        &pruned_messages
-            .iter()
-            .map(|m| m.msg.clone())
-            .collect::<Vec<ChatCompletionRequestMessage>>(),
-    );
-    let resp = timer!("openai chat completion", {
-        client.chat(&messages).await?
-    });
-    let resp_msg = Message::from_response(resp, info.build()?)?;
-    socket.send(HTMLMsg::from(&resp_msg)).await?;
-    history.assistant(resp_msg);
-    Ok(())
+async fn usage_summary(
+    State(state): State<Arc<AppState>>,
+    session: WritableSession,
+) -> Result<impl IntoResponse, ApiError> {
+    api::admin(&state, &session)?;
+    Ok(Json(state.bot.ledger.summary()))
 }
 
-async fn clear_history(mut session: WritableSession) -> impl IntoResponse {
-    session.destroy();
-    Redirect::to("/")
+/// The Prometheus metrics, for scrapers without a session.
+async fn metrics() -> Result<impl IntoResponse, ApiError> {
+    let content_type = [(header::CONTENT_TYPE, "text/plain; version=0.0.4")];
+    Ok((content_type, gpt_rs::metrics::render()?))
 }
 
-async fn index(mut session: WritableSession) -> impl IntoResponse {
-    let history = session
-        .get::<String>("hist")
-        .and_then(|filename| {
-            History::load(&filename)
-                .map_err(|e| error!("Couldn't open file {}: {}", filename, e))
-                .ok()
-        })
-        .unwrap_or_else(History::new);
-    if let Some(hist_name) = &history.name {
-        session.insert("hist", hist_name.to_string()).unwrap();
-    }
+async fn new_conversation(
+    State(state): State<Arc<AppState>>,
+    mut session: WritableSession,
+) -> Result<Redirect, ApiError> {
+    let Some(user) = auth::current_user(&state, &mut session) else {
+        return Ok(Redirect::to("/login"));
+    };
+    let conversation = state.conversations.create(&user)?;
+    session::open(&mut session, &conversation);
+    Ok(Redirect::to("/"))
+}
 
-    let history = history.messages().iter().map(HTMLMsg::from).collect();
+async fn open_conversation(
+    State(state): State<Arc<AppState>>,
+    mut session: WritableSession,
+    Path(id): Path<String>,
+) -> Result<Redirect, ApiError> {
+    let Some(user) = auth::current_user(&state, &mut session) else {
+        return Ok(Redirect::to("/login"));
+    };
+    let conversation = state
+        .conversations
+        .get_owned(&id, &user)
+        .map_err(ApiError::not_found)?;
+    session::open(&mut session, &conversation);
+    Ok(Redirect::to("/"))
+}
 
-    let template = IndexTemplate { history };
-    HtmlTemplate(template)
+async fn index(
+    State(state): State<Arc<AppState>>,
+    mut session: WritableSession,
+) -> Result<Response, ApiError> {
+    let Some(user) = auth::current_user(&state, &mut session) else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let conversation = session::current_conversation(&state.conversations, &mut session, &user)?;
+    let history = state.conversations.history(&conversation)?;
+
+    let seq = history.nodes().len();
+    let history = html::history_messages(&history);
+    let conversations = state.conversations.list(&user)?;
+
+    let template = IndexTemplate {
+        history,
+        seq,
+        conversations,
+        current: conversation.id,
+        username: auth::current_account(&state, &session).map(|user| user.username),
+    };
+    Ok(HtmlTemplate(template).into_response())
 }
 
 async fn shutdown_signal() {
@@ -249,5 +766,5 @@ async fn shutdown_signal() {
         _ = terminate => {},
     }
 
-    println!("signal received, starting graceful shutdown");
+    info!("signal received, starting graceful shutdown");
 }
@@ -0,0 +1,116 @@
+//! Prometheus metrics, served as text at `/metrics`.
+//!
+//! The metrics live in a registry of their own for the whole process, so
+//! they can be recorded anywhere without threading state through.
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use anyhow::Result;
+use prometheus::{
+    exponential_buckets, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use crate::usage::Usage;
+
+struct Metrics {
+    registry: Registry,
+    /// Duration of the stages of a turn timed with `timer!`.
+    stage_seconds: HistogramVec,
+    /// Duration of whole turns, by outcome.
+    turn_seconds: HistogramVec,
+    tokens: IntCounterVec,
+    /// Whether the summary of earlier messages could be reused.
+    summary_cache: IntCounterVec,
+    errors: IntCounterVec,
+}
+
+static METRICS: LazyLock<Metrics> =
+    LazyLock::new(|| Metrics::new().expect("metrics are registered once with distinct names"));
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("gpt_rs".to_string()), None)?;
+        // From 5ms to about 80s
+        let buckets = exponential_buckets(0.005, 2.0, 15)?;
+        let stage_seconds = HistogramVec::new(
+            HistogramOpts::new("stage_duration_seconds", "Duration of the stages of a turn")
+                .buckets(buckets.clone()),
+            &["stage"],
+        )?;
+        let turn_seconds = HistogramVec::new(
+            HistogramOpts::new("turn_duration_seconds", "Duration of answering a question")
+                .buckets(buckets),
+            &["outcome"],
+        )?;
+        let tokens = IntCounterVec::new(
+            Opts::new("tokens_total", "Tokens used by OpenAI calls"),
+            &["kind"],
+        )?;
+        let summary_cache = IntCounterVec::new(
+            Opts::new(
+                "summary_cache_total",
+                "Turns reusing the stored summary or summarizing again",
+            ),
+            &["result"],
+        )?;
+        let errors = IntCounterVec::new(Opts::new("errors_total", "Errors by kind"), &["kind"])?;
+
+        registry.register(Box::new(stage_seconds.clone()))?;
+        registry.register(Box::new(turn_seconds.clone()))?;
+        registry.register(Box::new(tokens.clone()))?;
+        registry.register(Box::new(summary_cache.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        Ok(Self {
+            registry,
+            stage_seconds,
+            turn_seconds,
+            tokens,
+            summary_cache,
+            errors,
+        })
+    }
+}
+
+pub fn stage(stage: &str, duration: Duration) {
+    METRICS
+        .stage_seconds
+        .with_label_values(&[stage])
+        .observe(duration.as_secs_f64());
+}
+
+/// Records an answered turn, `ok` unless answering failed.
+pub fn turn(duration: Duration, ok: bool) {
+    let outcome = if ok { "ok" } else { "error" };
+    METRICS
+        .turn_seconds
+        .with_label_values(&[outcome])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn tokens(usage: &Usage) {
+    let tokens = &METRICS.tokens;
+    tokens
+        .with_label_values(&["prompt"])
+        .inc_by(usage.prompt_tokens);
+    tokens
+        .with_label_values(&["completion"])
+        .inc_by(usage.completion_tokens);
+    tokens
+        .with_label_values(&["embedding"])
+        .inc_by(usage.embedding_tokens);
+}
+
+pub fn summary_cache(hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    METRICS.summary_cache.with_label_values(&[result]).inc();
+}
+
+/// Counts an error, e.g. `answer` for a failed turn or `rate_limited`.
+pub fn error(kind: &str) {
+    METRICS.errors.with_label_values(&[kind]).inc();
+}
+
+/// All metrics in the Prometheus text format.
+pub fn render() -> Result<String> {
+    Ok(TextEncoder::new().encode_to_string(&METRICS.registry.gather())?)
+}
@@ -1,55 +1,347 @@
-use crate::{CHAT_MODEL, EMBEDDING_MODEL};
+use crate::cassette::Cassette;
+use crate::config::OpenAIConfig;
+use crate::usage::Usage;
 use anyhow::Error;
 use async_openai::{
     types::{
-        ChatCompletionRequestMessage, ChatCompletionResponseMessage,
-        CreateChatCompletionRequestArgs, CreateEmbeddingRequestArgs,
+        ChatCompletionRequestMessage, ChatCompletionResponseMessage, CreateChatCompletionRequest,
+        CreateChatCompletionRequestArgs, CreateEmbeddingRequestArgs, Role,
     },
     Client as OpenAIClient,
 };
+use futures::StreamExt;
 use ndarray::Array1;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A streamed answer stopped by closing the receiver of its deltas.
+#[derive(Debug)]
+struct Stopped(ChatCompletionResponseMessage, Usage);
+
+impl fmt::Display for Stopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The answer was stopped")
+    }
+}
+
+impl std::error::Error for Stopped {}
+
+fn assistant(content: String) -> ChatCompletionResponseMessage {
+    ChatCompletionResponseMessage {
+        role: Role::Assistant,
+        content,
+    }
+}
 
 pub struct Client {
     client: OpenAIClient,
+    http: reqwest::Client,
+    chat_model: String,
+    embedding_model: String,
+    cassette: Option<Cassette>,
+}
+
+/// Chat message in the tool-calling format, which `async_openai` 0.10 doesn't support.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub typ: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Tool {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub parameters: Value,
+}
+
+impl ToolMessage {
+    pub fn new(role: &str, content: &str) -> Self {
+        Self {
+            role: role.to_string(),
+            content: Some(content.to_string()),
+            tool_calls: vec![],
+            tool_call_id: None,
+        }
+    }
+
+    pub fn tool_result(call: &ToolCall, content: String) -> Self {
+        Self {
+            role: "tool".to_string(),
+            content: Some(content),
+            tool_calls: vec![],
+            tool_call_id: Some(call.id.clone()),
+        }
+    }
+}
+
+impl From<&ChatCompletionRequestMessage> for ToolMessage {
+    fn from(msg: &ChatCompletionRequestMessage) -> Self {
+        let role = serde_json::to_value(&msg.role)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_else(|| "user".to_string());
+        Self::new(&role, &msg.content)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ToolResponse {
+    choices: Vec<ToolChoice>,
+    usage: Option<async_openai::types::Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ToolChoice {
+    message: ToolMessage,
 }
 
 impl Client {
-    pub fn new(api_key: &str) -> Self {
-        let client = OpenAIClient::new().with_api_key(api_key);
-        Self { client }
+    pub fn new(config: &OpenAIConfig) -> Result<Self, Error> {
+        let client = OpenAIClient::new().with_api_key(config.api_key.as_deref().unwrap_or_default());
+        let cassette = config.cassette.as_ref().map(Cassette::open).transpose()?;
+        Ok(Self {
+            client,
+            http: reqwest::Client::new(),
+            chat_model: config.chat_model.clone(),
+            embedding_model: config.embedding_model.clone(),
+            cassette,
+        })
     }
 
-    pub async fn get_embedding(&self, buffer: &str) -> Result<Array1<f32>, Error> {
+    /// The result of `call` for `request`, recorded or replayed with a
+    /// cassette.
+    async fn call<T>(
+        &self,
+        kind: &str,
+        request: &impl Serialize,
+        call: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        match &self.cassette {
+            Some(cassette) => cassette.call(kind, request, call).await,
+            None => call.await,
+        }
+    }
+
+    pub fn chat_model(&self) -> &str {
+        &self.chat_model
+    }
+
+    pub fn embedding_model(&self) -> &str {
+        &self.embedding_model
+    }
+
+    /// Whether responses are replayed from a cassette, without network.
+    pub fn offline(&self) -> bool {
+        self.cassette.as_ref().is_some_and(Cassette::replaying)
+    }
+
+    /// Checks that OpenAI accepts the API key, without using tokens.
+    pub async fn ping(&self) -> Result<(), Error> {
+        let response = self
+            .http
+            .get(format!("{}/models", self.client.api_base()))
+            .bearer_auth(self.client.api_key())
+            .timeout(PING_TIMEOUT)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            anyhow::bail!("OpenAI returned {}", response.status());
+        }
+        Ok(())
+    }
+
+    pub async fn get_embedding(&self, buffer: &str) -> Result<(Array1<f32>, Usage), Error> {
         let request = CreateEmbeddingRequestArgs::default()
-            .model(EMBEDDING_MODEL)
+            .model(&self.embedding_model)
             .input([buffer])
             .build()?;
 
-        let response = self.client.embeddings().create(request).await?;
-        let emb = Array1::from_vec(response.data[0].embedding.clone());
-        Ok(emb)
+        let (emb, usage) = self
+            .call("embedding", &request, async {
+                let response = self.client.embeddings().create(request.clone()).await?;
+                let emb = response.data[0].embedding.clone();
+                Ok((emb, Usage::from(&response.usage)))
+            })
+            .await?;
+        Ok((Array1::from_vec(emb), usage))
     }
 
     pub async fn chat(
         &self,
         messages: &[ChatCompletionRequestMessage],
-    ) -> Result<ChatCompletionResponseMessage, Error> {
+    ) -> Result<(ChatCompletionResponseMessage, Usage), Error> {
         //return Ok(ChatCompletionResponseMessage {
          //   role: Role::Assistant,
           //  content: "Answer".to_string(),
         //});
         let request = CreateChatCompletionRequestArgs::default()
-            .model(CHAT_MODEL)
+            .model(&self.chat_model)
             .messages(messages)
             .build()?;
 
-        let response = self.client.chat().create(request).await?;
+        self.call("chat", &request, async {
+            let response = self.client.chat().create(request.clone()).await?;
+            let usage = response.usage.as_ref().map(Usage::from).unwrap_or_default();
+
+            response
+                .choices
+                .into_iter()
+                .next()
+                .ok_or(anyhow::anyhow!("No reponse"))
+                .map(|c| (c.message, usage))
+        })
+        .await
+    }
+
+    /// Streaming chat completion: every piece of the answer is sent to `deltas`
+    /// as it arrives. The stream doesn't report usage, so it is estimated.
+    /// A replayed answer arrives in one piece. Closing the receiver stops the
+    /// answer, returning the part sent so far.
+    pub async fn chat_stream(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        deltas: &UnboundedSender<String>,
+    ) -> Result<(ChatCompletionResponseMessage, Usage), Error> {
+        if deltas.is_closed() {
+            return Ok((assistant(String::new()), Usage::default()));
+        }
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(&self.chat_model)
+            .messages(messages)
+            .stream(true)
+            .build()?;
+
+        let mut streamed = false;
+        let result = self
+            .call("chat", &request, async {
+                streamed = true;
+                self.stream(request.clone(), messages, deltas).await
+            })
+            .await;
+        let (message, usage) = match result.map_err(Error::downcast::<Stopped>) {
+            Ok(response) => response,
+            Err(Ok(Stopped(message, usage))) => return Ok((message, usage)),
+            Err(Err(e)) => return Err(e),
+        };
+        if !streamed {
+            let _ = deltas.send(message.content.clone());
+        }
+        Ok((message, usage))
+    }
+
+    async fn stream(
+        &self,
+        request: CreateChatCompletionRequest,
+        messages: &[ChatCompletionRequestMessage],
+        deltas: &UnboundedSender<String>,
+    ) -> Result<(ChatCompletionResponseMessage, Usage), Error> {
+        let mut stream = self.client.chat().create_stream(request).await?;
+        let mut content = String::new();
+        let mut stopped = false;
+        while let Some(response) = stream.next().await {
+            for choice in response?.choices {
+                if let Some(delta) = choice.delta.content {
+                    if deltas.send(delta.clone()).is_err() {
+                        stopped = true;
+                        break;
+                    }
+                    content.push_str(&delta);
+                }
+            }
+            if stopped {
+                break;
+            }
+        }
+
+        let prompt_tokens =
+            tiktoken_rs::async_openai::num_tokens_from_messages(&self.chat_model, messages)
+                .unwrap_or_default();
+        let completion_tokens = tiktoken_rs::cl100k_base_singleton()
+            .lock()
+            .encode_with_special_tokens(&content)
+            .len();
+        let usage = Usage {
+            prompt_tokens: prompt_tokens as u64,
+            completion_tokens: completion_tokens as u64,
+            embedding_tokens: 0,
+        };
+        if stopped {
+            // An error, so that the cassette doesn't record the partial answer
+            return Err(Stopped(assistant(content), usage).into());
+        }
+        Ok((assistant(content), usage))
+    }
+
+    /// Chat completion offering `tools` to the model. With `allow_tools == false`
+    /// the model is forced to produce a final answer.
+    pub async fn chat_with_tools(
+        &self,
+        messages: &[ToolMessage],
+        tools: &[Tool],
+        allow_tools: bool,
+    ) -> Result<(ToolMessage, Usage), Error> {
+        let tools: Vec<Value> = tools
+            .iter()
+            .map(|tool| json!({"type": "function", "function": tool}))
+            .collect();
+        let request = json!({
+            "model": self.chat_model,
+            "messages": messages,
+            "tools": tools,
+            "tool_choice": if allow_tools { "auto" } else { "none" },
+        });
+
+        self.call("tools", &request, async {
+            let response = self
+                .http
+                .post(format!("{}/chat/completions", self.client.api_base()))
+                .bearer_auth(self.client.api_key())
+                .json(&request)
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                let status = response.status();
+                anyhow::bail!("OpenAI returned {}: {}", status, response.text().await?);
+            }
+            let response: ToolResponse = response.json().await?;
+            let usage = response.usage.as_ref().map(Usage::from).unwrap_or_default();
 
-        response
-            .choices
-            .into_iter()
-            .next()
-            .ok_or(anyhow::anyhow!("No reponse"))
-            .map(|c| c.message)
+            response
+                .choices
+                .into_iter()
+                .next()
+                .ok_or(anyhow::anyhow!("No reponse"))
+                .map(|c| (c.message, usage))
+        })
+        .await
     }
 }
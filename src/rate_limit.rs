@@ -0,0 +1,364 @@
+//! Token buckets of `[rate_limit]`, per session, per client address and
+//! global.
+use std::{
+    fmt,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use hashlink::LruCache;
+use serde_json::json;
+
+use crate::api::AppState;
+use crate::api_keys::ApiKey;
+use crate::config::{BucketConfig, RateLimitConfig};
+use crate::metrics;
+use crate::session::{ACCOUNT_KEY, USER_KEY};
+
+/// Buckets kept per scope; the least recently used one makes room for a new
+/// client.
+const MAX_BUCKETS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &BucketConfig, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.burst),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &BucketConfig, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_minute / 60.0).min(f64::from(limit.burst));
+        self.updated = now;
+    }
+
+    /// Time until the bucket holds a token.
+    fn wait(&self, limit: &BucketConfig) -> Option<Duration> {
+        if self.tokens >= 1.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(
+            (1.0 - self.tokens) * 60.0 / limit.per_minute,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Session,
+    Ip,
+    Global,
+}
+
+/// A request over one of the limits.
+#[derive(Debug, Clone, Copy)]
+pub struct Limited {
+    pub scope: Scope,
+    pub retry_after: Duration,
+}
+
+impl Limited {
+    /// Whole seconds to wait, at least 1.
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after.as_secs_f64().ceil().max(1.0) as u64
+    }
+}
+
+impl fmt::Display for Limited {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let from = match self.scope {
+            Scope::Session => "from this session",
+            Scope::Ip => "from your address",
+            Scope::Global => "to the server",
+        };
+        write!(
+            f,
+            "Slow down: too many requests {}, try again in {} seconds",
+            from,
+            self.retry_after_secs()
+        )
+    }
+}
+
+struct Buckets {
+    sessions: LruCache<String, Bucket>,
+    ips: LruCache<IpAddr, Bucket>,
+    global: Option<Bucket>,
+}
+
+impl Default for Buckets {
+    fn default() -> Self {
+        Self {
+            sessions: LruCache::new(MAX_BUCKETS),
+            ips: LruCache::new(MAX_BUCKETS),
+            global: None,
+        }
+    }
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<Buckets>,
+}
+
+/// The refilled bucket of `key`, created full if needed, and marked as the
+/// most recently used.
+fn bucket<'a, K: Eq + Hash>(
+    buckets: &'a mut LruCache<K, Bucket>,
+    key: K,
+    limit: &BucketConfig,
+    now: Instant,
+) -> &'a mut Bucket {
+    if buckets.len() >= buckets.capacity() && buckets.peek(&key).is_none() {
+        buckets.remove_lru();
+    }
+    let bucket = buckets
+        .entry(key)
+        .or_insert_with(|| Bucket::new(limit, now));
+    bucket.refill(limit, now);
+    bucket
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Takes a token from every bucket of the client, or none if one of them
+    /// is empty.
+    pub fn check(&self, session: Option<&str>, ip: Option<IpAddr>) -> Result<(), Limited> {
+        let config = &self.config;
+        if !config.enabled {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut guard = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let buckets = &mut *guard;
+
+        let mut client = vec![];
+        if let Some(session) = session.filter(|_| config.session.per_minute > 0.0) {
+            let limit = &config.session;
+            let bucket = bucket(&mut buckets.sessions, session.to_string(), limit, now);
+            client.push((Scope::Session, limit, bucket));
+        }
+        if let Some(ip) = ip.filter(|_| config.ip.per_minute > 0.0) {
+            let limit = &config.ip;
+            client.push((Scope::Ip, limit, bucket(&mut buckets.ips, ip, limit, now)));
+        }
+        if config.global.per_minute > 0.0 {
+            let limit = &config.global;
+            let bucket = buckets
+                .global
+                .get_or_insert_with(|| Bucket::new(limit, now));
+            bucket.refill(limit, now);
+            client.push((Scope::Global, limit, bucket));
+        }
+
+        let limited = client
+            .iter()
+            .filter_map(|(scope, limit, bucket)| {
+                let retry_after = bucket.wait(limit)?;
+                Some(Limited {
+                    scope: *scope,
+                    retry_after,
+                })
+            })
+            .max_by_key(|limited| limited.retry_after);
+        if let Some(limited) = limited {
+            metrics::error("rate_limited");
+            return Err(limited);
+        }
+        for (_, _, bucket) in client {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    /// The address of the client: the peer, or with `trust_forwarded_for`
+    /// the address of `X-Forwarded-For` appended by the first of the
+    /// `trusted_proxies`. The addresses before it come from the client, who
+    /// may send any.
+    pub fn client_ip(&self, headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+        if self.config.trust_forwarded_for {
+            let forwarded = headers
+                .get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.rsplit(',').nth(self.config.trusted_proxies - 1))
+                .and_then(|ip| ip.trim().parse().ok());
+            if let Some(ip) = forwarded {
+                return ip;
+            }
+        }
+        peer.ip()
+    }
+}
+
+/// Key of the session bucket of an HTTP request: the id of its API key,
+/// verified by `api_keys::authenticate` before, or the user of its session
+/// cookie. Requests without either are only limited by address.
+fn session_key<B>(request: &Request<B>) -> Option<String> {
+    if let Some(key) = request.extensions().get::<ApiKey>() {
+        return Some(format!("key:{}", key.id));
+    }
+    let session = request
+        .extensions()
+        .get::<axum_sessions::SessionHandle>()?
+        .try_read()
+        .ok()?;
+    session
+        .get::<String>(ACCOUNT_KEY)
+        .or_else(|| session.get::<String>(USER_KEY))
+}
+
+pub async fn limit<B>(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let limiter = &state.rate_limiter;
+    let ip = limiter.client_ip(request.headers(), peer);
+    if let Err(limited) = limiter.check(session_key(&request).as_deref(), Some(ip)) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, limited.retry_after_secs().to_string())],
+            Json(json!({ "error": limited.to_string() })),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: BucketConfig = BucketConfig {
+        per_minute: 60.0,
+        burst: 3,
+    };
+
+    #[test]
+    fn bucket_refills_up_to_the_burst() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(&LIMIT, start);
+        assert_eq!(bucket.tokens, 3.0);
+        bucket.tokens = 0.0;
+
+        bucket.refill(&LIMIT, start + Duration::from_millis(1500));
+        assert!((bucket.tokens - 1.5).abs() < 1e-9);
+        bucket.refill(&LIMIT, start + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 3.0);
+    }
+
+    #[test]
+    fn bucket_waits_for_the_next_token() {
+        let mut bucket = Bucket::new(&LIMIT, Instant::now());
+        assert_eq!(bucket.wait(&LIMIT), None);
+        bucket.tokens = 0.25;
+        assert_eq!(bucket.wait(&LIMIT), Some(Duration::from_millis(750)));
+    }
+
+    #[test]
+    fn check_takes_a_token_from_every_bucket() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            session: LIMIT,
+            ip: BucketConfig {
+                per_minute: 60.0,
+                burst: 4,
+            },
+            ..RateLimitConfig::default()
+        });
+        let ip = Some(IpAddr::from([127, 0, 0, 1]));
+        for _ in 0..3 {
+            limiter.check(Some("alice"), ip).unwrap();
+        }
+        let limited = limiter.check(Some("alice"), ip).unwrap_err();
+        assert_eq!(limited.scope, Scope::Session);
+        assert_eq!(limited.retry_after_secs(), 1);
+
+        // The rejected request took nothing
+        limiter.check(Some("bob"), ip).unwrap();
+        assert_eq!(
+            limiter.check(Some("carol"), ip).unwrap_err().scope,
+            Scope::Ip
+        );
+    }
+
+    #[test]
+    fn buckets_drop_the_least_recently_used() {
+        let now = Instant::now();
+        let mut buckets = LruCache::new(2);
+        for key in ["a", "b", "a", "c"] {
+            bucket(&mut buckets, key, &LIMIT, now).tokens -= 1.0;
+        }
+        assert_eq!(buckets.len(), 2);
+        assert!(buckets.peek("b").is_none());
+        assert_eq!(buckets.peek("a").unwrap().tokens, 1.0);
+        assert_eq!(buckets.peek("c").unwrap().tokens, 2.0);
+    }
+
+    #[test]
+    fn session_key_only_trusts_authenticated_keys() {
+        let request = || {
+            Request::builder()
+                .header(header::AUTHORIZATION, "Bearer gptrs_victim_guess")
+                .body(())
+                .unwrap()
+        };
+        assert_eq!(session_key(&request()), None);
+
+        let mut request = request();
+        request.extensions_mut().insert(ApiKey {
+            id: "k1".to_string(),
+            name: "test".to_string(),
+            owner: "alice".to_string(),
+            hash: String::new(),
+            created: chrono::Utc::now(),
+            revoked: None,
+            requests_per_day: None,
+            tokens_per_day: None,
+        });
+        assert_eq!(session_key(&request).as_deref(), Some("key:k1"));
+    }
+
+    #[test]
+    fn client_ip_trusts_the_last_proxies() {
+        let peer = SocketAddr::from(([10, 0, 0, 1], 1234));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "1.1.1.1, 2.2.2.2, 3.3.3.3".parse().unwrap(),
+        );
+        let limiter = |trust_forwarded_for, trusted_proxies| {
+            RateLimiter::new(RateLimitConfig {
+                trust_forwarded_for,
+                trusted_proxies,
+                ..RateLimitConfig::default()
+            })
+        };
+        let ip = |limiter: RateLimiter| limiter.client_ip(&headers, peer).to_string();
+        assert_eq!(ip(limiter(false, 1)), "10.0.0.1");
+        assert_eq!(ip(limiter(true, 1)), "3.3.3.3");
+        assert_eq!(ip(limiter(true, 2)), "2.2.2.2");
+        assert_eq!(ip(limiter(true, 4)), "10.0.0.1");
+    }
+}
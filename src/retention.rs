@@ -0,0 +1,277 @@
+//! Periodic removal of conversations according to `[retention]`.
+use std::{collections::HashMap, fmt, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use tracing::{error, info};
+
+use crate::api::AppState;
+use crate::config::RetentionConfig;
+use crate::hub::Hub;
+use crate::store::{Entry, HistoryStore};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// A conversation without messages.
+    Empty,
+    /// A history without metadata or owner, kept only for sessions which
+    /// predate conversations.
+    Orphaned,
+    Expired,
+    /// The owner has more than `max_conversations_per_user`.
+    UserLimit,
+    /// The store is larger than `max_size_mb`.
+    SizeLimit,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Reason::Empty => "empty",
+            Reason::Orphaned => "orphaned",
+            Reason::Expired => "expired",
+            Reason::UserLimit => "over the per-user limit",
+            Reason::SizeLimit => "over the size limit",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Removal {
+    pub entry: Entry,
+    pub reason: Reason,
+}
+
+/// Decides what to remove from `entries`. Empty, orphaned and expired
+/// histories go first; the per-user and size limits then remove the least
+/// recently updated of the remaining conversations.
+pub fn plan(entries: Vec<Entry>, policy: &RetentionConfig, now: DateTime<Utc>) -> Vec<Removal> {
+    let grace = chrono::Duration::hours(policy.grace_hours as i64);
+    let max_age = policy
+        .max_age_days
+        .map(|days| chrono::Duration::days(days as i64));
+
+    let mut removals = vec![];
+    let mut kept = vec![];
+    for entry in entries {
+        let age = now - entry.modified;
+        let unowned = entry
+            .conversation
+            .as_ref()
+            .is_none_or(|c| c.owner.is_empty());
+        let reason = if unowned && age > grace {
+            Some(Reason::Orphaned)
+        } else if entry.messages == 0 && age > grace {
+            Some(Reason::Empty)
+        } else if max_age.is_some_and(|max_age| age > max_age) {
+            Some(Reason::Expired)
+        } else {
+            None
+        };
+        match reason {
+            Some(reason) => removals.push(Removal { entry, reason }),
+            None => kept.push(entry),
+        }
+    }
+
+    // Most recently updated first
+    kept.sort_by_key(|e| std::cmp::Reverse(e.modified));
+
+    if let Some(max) = policy.max_conversations_per_user {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        let mut within = vec![];
+        for entry in kept {
+            let Some(owner) = entry.conversation.as_ref().map(|c| c.owner.clone()) else {
+                within.push(entry);
+                continue;
+            };
+            let count = counts.entry(owner).or_default();
+            *count += 1;
+            if *count > max {
+                removals.push(Removal {
+                    entry,
+                    reason: Reason::UserLimit,
+                });
+            } else {
+                within.push(entry);
+            }
+        }
+        kept = within;
+    }
+
+    if let Some(max_mb) = policy.max_size_mb {
+        let max = max_mb * 1024 * 1024;
+        let mut total: u64 = kept.iter().map(|e| e.size).sum();
+        while total > max {
+            let Some(entry) = kept.pop() else {
+                break;
+            };
+            total -= entry.size;
+            removals.push(Removal {
+                entry,
+                reason: Reason::SizeLimit,
+            });
+        }
+    }
+
+    removals
+}
+
+/// Runs the policy once and logs every removal; nothing is deleted with
+/// `dry_run`. Conversations with a room open in `hub` are skipped, their
+/// writer would store them again; `gpt-rs retention` has no hub to check.
+pub fn enforce(
+    store: &dyn HistoryStore,
+    policy: &RetentionConfig,
+    dry_run: bool,
+    hub: Option<&Hub>,
+) -> Result<Vec<Removal>> {
+    let verb = if dry_run { "would remove" } else { "removing" };
+    let mut removals = vec![];
+    for removal in plan(store.entries()?, policy, Utc::now()) {
+        let entry = &removal.entry;
+        if !dry_run {
+            let delete = || store.delete_conversation(&entry.id);
+            let deleted = match hub {
+                Some(hub) => hub.unless_open(&entry.id, delete),
+                None => Some(delete()),
+            };
+            let Some(deleted) = deleted else {
+                info!("retention: skipping {}, which is open", entry.id);
+                continue;
+            };
+            deleted?;
+        }
+        info!(
+            "retention: {} {} ({}, {} messages, {} bytes, updated {})",
+            verb, entry.id, removal.reason, entry.messages, entry.size, entry.modified
+        );
+        removals.push(removal);
+    }
+    let size: u64 = removals.iter().map(|r| r.entry.size).sum();
+    info!(
+        "retention: {} {} conversations, {} bytes",
+        verb,
+        removals.len(),
+        size
+    );
+    Ok(removals)
+}
+
+/// Enforces `retention` every `interval_minutes`, starting now.
+pub fn spawn(state: Arc<AppState>, store: Arc<dyn HistoryStore>) {
+    tokio::spawn(async move {
+        let policy = state.bot.config.retention.clone();
+        let mut interval = tokio::time::interval(Duration::from_secs(policy.interval_minutes * 60));
+        loop {
+            interval.tick().await;
+            let (state, store, policy) = (state.clone(), store.clone(), policy.clone());
+            let result = tokio::task::spawn_blocking(move || {
+                enforce(store.as_ref(), &policy, policy.dry_run, Some(&state.hub)).map(|_| ())
+            })
+            .await;
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("retention failed: {:#}", e),
+                Err(e) => error!("retention task panicked: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversations::Conversation;
+
+    fn entry(id: &str, owner: Option<&str>, messages: usize, hours: i64) -> Entry {
+        let modified = Utc::now() - chrono::Duration::hours(hours);
+        Entry {
+            id: id.to_string(),
+            conversation: owner.map(|owner| Conversation {
+                id: id.to_string(),
+                owner: owner.to_string(),
+                title: String::new(),
+                created: modified,
+                updated: modified,
+            }),
+            messages,
+            size: 1024 * 1024,
+            modified,
+        }
+    }
+
+    fn policy() -> RetentionConfig {
+        RetentionConfig {
+            max_age_days: None,
+            max_size_mb: None,
+            max_conversations_per_user: None,
+            grace_hours: 24,
+            ..RetentionConfig::default()
+        }
+    }
+
+    fn removed(entries: Vec<Entry>, policy: &RetentionConfig) -> Vec<(String, Reason)> {
+        plan(entries, policy, Utc::now())
+            .into_iter()
+            .map(|r| (r.entry.id, r.reason))
+            .collect()
+    }
+
+    #[test]
+    fn removes_empty_and_orphaned_after_the_grace_period() {
+        let entries = vec![
+            entry("new", Some("alice"), 0, 1),
+            entry("empty", Some("alice"), 0, 48),
+            entry("orphan", None, 3, 48),
+            entry("unclaimed", Some(""), 3, 48),
+            entry("kept", Some("alice"), 3, 48),
+        ];
+        assert_eq!(
+            removed(entries, &policy()),
+            [
+                ("empty".to_string(), Reason::Empty),
+                ("orphan".to_string(), Reason::Orphaned),
+                ("unclaimed".to_string(), Reason::Orphaned),
+            ]
+        );
+    }
+
+    #[test]
+    fn removes_expired() {
+        let policy = RetentionConfig {
+            max_age_days: Some(2),
+            ..policy()
+        };
+        let entries = vec![
+            entry("old", Some("alice"), 3, 72),
+            entry("recent", Some("alice"), 3, 24),
+        ];
+        assert_eq!(
+            removed(entries, &policy),
+            [("old".to_string(), Reason::Expired)]
+        );
+    }
+
+    #[test]
+    fn limits_remove_the_least_recently_updated() {
+        let policy = RetentionConfig {
+            max_conversations_per_user: Some(2),
+            max_size_mb: Some(2),
+            ..policy()
+        };
+        let entries = vec![
+            entry("a3", Some("alice"), 3, 3),
+            entry("a1", Some("alice"), 3, 1),
+            entry("b1", Some("bob"), 3, 0),
+            entry("a2", Some("alice"), 3, 2),
+        ];
+        assert_eq!(
+            removed(entries, &policy),
+            [
+                ("a3".to_string(), Reason::UserLimit),
+                ("a2".to_string(), Reason::SizeLimit),
+            ]
+        );
+    }
+}
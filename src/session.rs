@@ -0,0 +1,160 @@
+use anyhow::Result;
+use axum_sessions::extractors::WritableSession;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use tracing::error;
+
+use crate::conversations::{Conversation, Conversations};
+use crate::users::User;
+
+pub const USER_KEY: &str = "user";
+/// Id of the logged in account, when `auth.enabled`.
+pub const ACCOUNT_KEY: &str = "account";
+pub const CONVERSATION_KEY: &str = "conversation";
+/// History of sessions created before conversations existed.
+const LEGACY_HISTORY_KEY: &str = "hist";
+
+pub fn random_id() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect()
+}
+
+/// Id of the user of the session, created on first use.
+pub fn user_id(session: &mut WritableSession) -> String {
+    if let Some(user) = session.get::<String>(USER_KEY) {
+        return user;
+    }
+    let user = random_id();
+    if let Err(e) = session.insert(USER_KEY, &user) {
+        error!("Couldn't store user in session: {}", e);
+    }
+    user
+}
+
+/// Id of the account logged in to the session.
+pub fn account(session: &WritableSession) -> Option<String> {
+    session.get::<String>(ACCOUNT_KEY)
+}
+
+/// Logs `user` in; conversations of the anonymous user of the session become
+/// theirs.
+pub fn login(
+    conversations: &Conversations,
+    session: &mut WritableSession,
+    user: &User,
+) -> Result<()> {
+    if let Some(anonymous) = session.get::<String>(USER_KEY) {
+        if anonymous != user.id && session.get::<String>(ACCOUNT_KEY).is_none() {
+            conversations.transfer(&anonymous, &user.id)?;
+        }
+    }
+    session.insert(ACCOUNT_KEY, &user.id)?;
+    session.insert(USER_KEY, &user.id)?;
+    Ok(())
+}
+
+/// Removes the session cookie. Session data lives in the signed cookie, so
+/// a copy of it stays valid until the session expires.
+pub fn logout(session: &mut WritableSession) {
+    session.destroy();
+}
+
+/// The conversation of `user` open in the session: the one stored in the
+/// session, the legacy history file, the most recently updated one or a new
+/// one.
+pub fn current_conversation(
+    conversations: &Conversations,
+    session: &mut WritableSession,
+    user: &str,
+) -> Result<Conversation> {
+    let stored = session.get::<String>(CONVERSATION_KEY);
+    if let Some(conversation) = stored.and_then(|id| conversations.get_owned(&id, user).ok()) {
+        return Ok(conversation);
+    }
+
+    let conversation = match session.get::<String>(LEGACY_HISTORY_KEY) {
+        Some(id) if conversations.claimable(&id)? => {
+            session.remove(LEGACY_HISTORY_KEY);
+            conversations.adopt(&id, user)?
+        }
+        _ => match conversations.list(user)?.into_iter().next() {
+            Some(conversation) => conversation,
+            None => conversations.create(user)?,
+        },
+    };
+    open(session, &conversation);
+    Ok(conversation)
+}
+
+pub fn open(session: &mut WritableSession, conversation: &Conversation) {
+    if let Err(e) = session.insert(CONVERSATION_KEY, &conversation.id) {
+        error!("Couldn't store conversation in session: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{extract::FromRequestParts, http::Request};
+    use axum_sessions::{async_session::Session, SessionHandle};
+    use chrono::Utc;
+    use tokio::sync::RwLock;
+
+    use super::*;
+    use crate::store::JsonlStore;
+
+    async fn session() -> WritableSession {
+        let handle: SessionHandle = Arc::new(RwLock::new(Session::new()));
+        let (mut parts, _) = Request::new(()).into_parts();
+        parts.extensions.insert(handle);
+        let Ok(session) = WritableSession::from_request_parts(&mut parts, &()).await;
+        session
+    }
+
+    fn user(id: &str) -> User {
+        User {
+            id: id.to_string(),
+            username: id.to_string(),
+            password_hash: None,
+            oidc: None,
+            created: Utc::now(),
+        }
+    }
+
+    fn owned(conversations: &Conversations, owner: &str) -> Vec<String> {
+        let list = conversations.list(owner).unwrap();
+        list.into_iter().map(|c| c.id).collect()
+    }
+
+    #[tokio::test]
+    async fn login_transfers_the_anonymous_conversations() {
+        let dir = tempfile::tempdir().unwrap();
+        let conversations = Conversations::new(Arc::new(JsonlStore::new(dir.path())));
+        let mut session = session().await;
+        let anonymous = user_id(&mut session);
+        let conversation = conversations.create(&anonymous).unwrap();
+
+        login(&conversations, &mut session, &user("alice")).unwrap();
+        assert_eq!(account(&session).as_deref(), Some("alice"));
+        assert_eq!(user_id(&mut session), "alice");
+        assert_eq!(owned(&conversations, "alice"), [conversation.id]);
+        assert!(owned(&conversations, &anonymous).is_empty());
+    }
+
+    #[tokio::test]
+    async fn login_keeps_the_conversations_of_the_previous_account() {
+        let dir = tempfile::tempdir().unwrap();
+        let conversations = Conversations::new(Arc::new(JsonlStore::new(dir.path())));
+        let mut session = session().await;
+        login(&conversations, &mut session, &user("alice")).unwrap();
+        let conversation = conversations.create("alice").unwrap();
+
+        login(&conversations, &mut session, &user("bob")).unwrap();
+        assert_eq!(account(&session).as_deref(), Some("bob"));
+        assert_eq!(owned(&conversations, "alice"), [conversation.id]);
+        assert!(owned(&conversations, "bob").is_empty());
+    }
+}
@@ -0,0 +1,210 @@
+//! Persistence of conversations and their messages.
+//!
+//! `HistoryStore` is implemented by `JsonlStore`, one JSON lines file per
+//! conversation as written since the first version, and by `SqliteStore`.
+//! `migrate` imports the history files of the former into the latter.
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use tracing::info;
+
+use crate::config::{Config, StorageBackend};
+use crate::conversations::{title_from, Conversation};
+use crate::history::{Feedback, Message};
+
+mod jsonl;
+mod sqlite;
+
+pub use jsonl::JsonlStore;
+pub use sqlite::SqliteStore;
+
+pub trait HistoryStore: Send + Sync {
+    /// Inserts or updates the metadata of a conversation.
+    fn save_conversation(&self, conversation: &Conversation) -> Result<()>;
+    fn conversation(&self, id: &str) -> Result<Option<Conversation>>;
+    /// Conversations of `owner`, in no particular order.
+    fn conversations(&self, owner: &str) -> Result<Vec<Conversation>>;
+    /// Deletes a conversation together with its messages.
+    fn delete_conversation(&self, id: &str) -> Result<()>;
+    /// Whether messages may be stored under `id`, even without metadata.
+    fn history_exists(&self, id: &str) -> Result<bool>;
+    fn messages(&self, id: &str) -> Result<Vec<Message<'static>>>;
+    fn append_message(&self, id: &str, message: &Message) -> Result<()>;
+    /// Records feedback on `message` of conversation `id`, replacing earlier
+    /// feedback on it.
+    fn save_feedback(&self, id: &str, message: &str, feedback: &Feedback) -> Result<()>;
+    /// Feedback on the messages of conversation `id`, by message id.
+    fn feedback(&self, id: &str) -> Result<HashMap<String, Feedback>>;
+    /// Everything stored, including histories without metadata.
+    fn entries(&self) -> Result<Vec<Entry>>;
+}
+
+/// A stored history as seen by the retention policy.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub id: String,
+    /// `None` for history files without metadata.
+    pub conversation: Option<Conversation>,
+    pub messages: usize,
+    /// Bytes taken by the messages.
+    pub size: u64,
+    /// Last update of the conversation, or of the file without metadata.
+    pub modified: DateTime<Utc>,
+}
+
+/// Opens the store selected by `storage.backend`.
+pub fn open(config: &Config) -> Result<Arc<dyn HistoryStore>> {
+    Ok(match config.storage.backend {
+        StorageBackend::Jsonl => {
+            let dir = &config.paths.history_dir;
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("paths.history_dir: cannot create {}", dir.display()))?;
+            Arc::new(JsonlStore::new(dir))
+        }
+        StorageBackend::Sqlite => Arc::new(SqliteStore::open(&config.storage.database)?),
+    })
+}
+
+#[derive(Debug, Default)]
+pub struct Migration {
+    pub conversations: usize,
+    pub messages: usize,
+    /// Conversations already present in the target store.
+    pub skipped: usize,
+}
+
+/// Copies every history file of `from` into `to`, each in one transaction.
+/// History files written before conversations existed get unowned metadata,
+/// titled after their first user message, so they can still be adopted by
+/// their session.
+///
+/// Conversations already in `to` are skipped unless they have fewer messages,
+/// e.g. when an earlier migration was interrupted, and are copied again then.
+pub fn migrate(from: &JsonlStore, to: &SqliteStore) -> Result<Migration> {
+    let mut migration = Migration::default();
+    for id in from.history_ids()? {
+        let messages = from
+            .messages(&id)
+            .with_context(|| format!("Couldn't read history {}", id))?;
+        if to.conversation(&id)?.is_some() && to.messages(&id)?.len() >= messages.len() {
+            migration.skipped += 1;
+            continue;
+        }
+        let conversation = match from.conversation(&id)? {
+            Some(conversation) => conversation,
+            None => {
+                let modified = from.modified(&id)?;
+                let first = messages.iter().find(|m| m.class() == "user");
+                Conversation {
+                    id: id.clone(),
+                    owner: String::new(),
+                    title: first.map(|m| title_from(m.content())).unwrap_or_default(),
+                    created: modified,
+                    updated: modified,
+                }
+            }
+        };
+        to.import(&conversation, &messages, &from.feedback(&id)?)
+            .with_context(|| format!("Couldn't migrate history {}", id))?;
+        info!("migrated {} ({} messages)", id, messages.len());
+        migration.conversations += 1;
+        migration.messages += messages.len();
+    }
+    Ok(migration)
+}
+
+#[cfg(test)]
+mod tests {
+    use async_openai::types::Role;
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::history::Rating;
+
+    fn message(id: &str, role: Role, text: &str) -> Message<'static> {
+        let mut message = Message::new(role, text);
+        message.id = id.to_string();
+        message
+    }
+
+    fn stores() -> (tempfile::TempDir, JsonlStore, SqliteStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let history = dir.path().join("history");
+        std::fs::create_dir(&history).unwrap();
+        let sqlite = SqliteStore::open(&dir.path().join("history.db")).unwrap();
+        (dir, JsonlStore::new(&history), sqlite)
+    }
+
+    #[test]
+    fn migrate_copies_conversations_messages_and_feedback() {
+        let (_dir, jsonl, sqlite) = stores();
+        let created = Utc.with_ymd_and_hms(2023, 5, 1, 10, 0, 0).unwrap();
+        let conversation = Conversation {
+            id: "c1".to_string(),
+            owner: "alice".to_string(),
+            title: "Greetings".to_string(),
+            created,
+            updated: created,
+        };
+        jsonl.save_conversation(&conversation).unwrap();
+        jsonl
+            .append_message("c1", &message("q1", Role::User, "Hello"))
+            .unwrap();
+        jsonl
+            .append_message("c1", &message("a1", Role::Assistant, "Hi"))
+            .unwrap();
+        let feedback = Feedback {
+            rating: Rating::Up,
+            comment: Some("Nice".to_string()),
+            created,
+        };
+        jsonl.save_feedback("c1", "a1", &feedback).unwrap();
+        // A history written before conversations existed
+        jsonl
+            .append_message("old", &message("", Role::User, "  What is Rust?  "))
+            .unwrap();
+
+        let migration = migrate(&jsonl, &sqlite).unwrap();
+        assert_eq!(migration.conversations, 2);
+        assert_eq!(migration.messages, 3);
+        assert_eq!(migration.skipped, 0);
+
+        let migrated = sqlite.conversation("c1").unwrap().unwrap();
+        assert_eq!(migrated.owner, "alice");
+        assert_eq!(migrated.title, "Greetings");
+        let contents: Vec<_> = sqlite
+            .messages("c1")
+            .unwrap()
+            .iter()
+            .map(|m| m.content().to_string())
+            .collect();
+        assert_eq!(contents, ["Hello", "Hi"]);
+        let feedback = sqlite.feedback("c1").unwrap();
+        assert_eq!(feedback["a1"].rating, Rating::Up);
+        assert_eq!(feedback["a1"].comment.as_deref(), Some("Nice"));
+
+        let old = sqlite.conversation("old").unwrap().unwrap();
+        assert_eq!(old.owner, "");
+        assert_eq!(old.title, "What is Rust?");
+    }
+
+    #[test]
+    fn migrate_skips_copied_conversations_and_resumes_interrupted_ones() {
+        let (_dir, jsonl, sqlite) = stores();
+        for id in ["a", "b"] {
+            jsonl
+                .append_message(id, &message("q1", Role::User, "Hello"))
+                .unwrap();
+        }
+        assert_eq!(migrate(&jsonl, &sqlite).unwrap().conversations, 2);
+
+        jsonl
+            .append_message("b", &message("a1", Role::Assistant, "Hi"))
+            .unwrap();
+        let migration = migrate(&jsonl, &sqlite).unwrap();
+        assert_eq!(migration.conversations, 1);
+        assert_eq!(migration.skipped, 1);
+        assert_eq!(sqlite.messages("b").unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{Entry, HistoryStore};
+use crate::conversations::{valid_id, Conversation};
+use crate::history::{Feedback, Message};
+
+const META_SUFFIX: &str = ".meta.json";
+const FEEDBACK_SUFFIX: &str = ".feedback.jsonl";
+
+/// Messages are appended to the file named by the conversation id, the
+/// metadata is kept in `<id>.meta.json` next to it and feedback is appended
+/// to `<id>.feedback.jsonl`.
+pub struct JsonlStore {
+    dir: PathBuf,
+}
+
+impl JsonlStore {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// Ids of all history files, with or without metadata.
+    pub fn history_ids(&self) -> Result<Vec<String>> {
+        let mut ids = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if valid_id(&name) && entry.file_type()?.is_file() {
+                ids.push(name);
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Last modification time of the history file.
+    pub fn modified(&self, id: &str) -> Result<DateTime<Utc>> {
+        Ok(fs::metadata(self.dir.join(id))?.modified()?.into())
+    }
+
+    fn meta_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", id, META_SUFFIX))
+    }
+
+    fn feedback_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", id, FEEDBACK_SUFFIX))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct FeedbackLine<M, F> {
+    message: M,
+    feedback: F,
+}
+
+impl HistoryStore for JsonlStore {
+    fn save_conversation(&self, conversation: &Conversation) -> Result<()> {
+        // Create the history file so the conversation exists without messages
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.dir.join(&conversation.id))?;
+        let file = File::create(self.meta_path(&conversation.id))?;
+        serde_json::to_writer(file, conversation)?;
+        Ok(())
+    }
+
+    fn conversation(&self, id: &str) -> Result<Option<Conversation>> {
+        let path = self.meta_path(id);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let conversation = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        Ok(Some(conversation))
+    }
+
+    fn conversations(&self, owner: &str) -> Result<Vec<Conversation>> {
+        let mut conversations = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let Some(id) = name.to_str().and_then(|n| n.strip_suffix(META_SUFFIX)) else {
+                continue;
+            };
+            // One unreadable conversation doesn't hide the others
+            match self.conversation(id) {
+                Ok(Some(conversation)) if conversation.owner == owner => {
+                    conversations.push(conversation)
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Skipping conversation {}: {:#}", id, e),
+            }
+        }
+        Ok(conversations)
+    }
+
+    fn delete_conversation(&self, id: &str) -> Result<()> {
+        for path in [
+            self.dir.join(id),
+            self.meta_path(id),
+            self.feedback_path(id),
+        ] {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn history_exists(&self, id: &str) -> Result<bool> {
+        Ok(self.dir.join(id).is_file())
+    }
+
+    fn messages(&self, id: &str) -> Result<Vec<Message<'static>>> {
+        let path = self.dir.join(id);
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        let reader = BufReader::new(File::open(path)?);
+        let mut messages = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            messages.push(serde_json::from_str(&line)?);
+        }
+        Ok(messages)
+    }
+
+    fn append_message(&self, id: &str, message: &Message) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.dir.join(id))?;
+        serde_json::to_writer(&file, message)?;
+        file.write_all(b"\n")?;
+        file.flush()?;
+        Ok(())
+    }
+
+    fn save_feedback(&self, id: &str, message: &str, feedback: &Feedback) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.feedback_path(id))?;
+        serde_json::to_writer(&file, &FeedbackLine { message, feedback })?;
+        file.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn feedback(&self, id: &str) -> Result<HashMap<String, Feedback>> {
+        let path = self.feedback_path(id);
+        let mut feedback = HashMap::new();
+        if !path.exists() {
+            return Ok(feedback);
+        }
+        // Later lines replace earlier feedback on the same message
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line: FeedbackLine<String, Feedback> = serde_json::from_str(&line?)?;
+            feedback.insert(line.message, line.feedback);
+        }
+        Ok(feedback)
+    }
+
+    fn entries(&self) -> Result<Vec<Entry>> {
+        let mut entries = vec![];
+        for id in self.history_ids()? {
+            let path = self.dir.join(&id);
+            let messages = BufReader::new(File::open(&path)?).lines().count();
+            let conversation = self.conversation(&id)?;
+            entries.push(Entry {
+                modified: match &conversation {
+                    Some(conversation) => conversation.updated,
+                    None => self.modified(&id)?,
+                },
+                id,
+                conversation,
+                messages,
+                size: fs::metadata(path)?.len(),
+            });
+        }
+        // Metadata whose history file is gone
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let Some(id) = name.to_str().and_then(|n| n.strip_suffix(META_SUFFIX)) else {
+                continue;
+            };
+            if !self.dir.join(id).exists() {
+                let conversation = self.conversation(id)?;
+                entries.push(Entry {
+                    id: id.to_string(),
+                    modified: conversation.as_ref().map_or_else(Utc::now, |c| c.updated),
+                    conversation,
+                    messages: 0,
+                    size: 0,
+                });
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation(id: &str, owner: &str) -> Conversation {
+        Conversation {
+            id: id.to_string(),
+            owner: owner.to_string(),
+            title: String::new(),
+            created: Utc::now(),
+            updated: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn conversations_skip_unreadable_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = JsonlStore::new(dir.path());
+        store
+            .save_conversation(&conversation("a1", "alice"))
+            .unwrap();
+        store.save_conversation(&conversation("b2", "bob")).unwrap();
+        fs::write(store.meta_path("c3"), "{not json").unwrap();
+
+        let ids: Vec<_> = store
+            .conversations("alice")
+            .unwrap()
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(ids, ["a1"]);
+        assert!(store.conversation("c3").is_err());
+    }
+}
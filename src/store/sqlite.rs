@@ -0,0 +1,438 @@
+use std::{collections::HashMap, path::Path, sync::Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use async_openai::types::{ChatCompletionRequestMessage, Role};
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use super::{Entry, HistoryStore};
+use crate::conversations::Conversation;
+use crate::history::{Feedback, Info, Message, Rating};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS conversations (
+    id TEXT PRIMARY KEY,
+    owner TEXT NOT NULL,
+    title TEXT NOT NULL,
+    created TEXT NOT NULL,
+    updated TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS conversations_owner ON conversations (owner, updated);
+
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    conversation_id TEXT NOT NULL REFERENCES conversations (id) ON DELETE CASCADE,
+    role TEXT NOT NULL,
+    content TEXT NOT NULL,
+    tokens INTEGER NOT NULL,
+    created TEXT NOT NULL,
+    -- Id of the message within its conversation and of the one it follows
+    uid TEXT,
+    parent_uid TEXT,
+    -- 1 for answers stopped by the user
+    cancelled INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS messages_conversation ON messages (conversation_id, id);
+
+-- Answer details; usage and cost are kept in columns for reporting queries.
+CREATE TABLE IF NOT EXISTS info (
+    message_id INTEGER PRIMARY KEY REFERENCES messages (id) ON DELETE CASCADE,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    embedding_tokens INTEGER NOT NULL,
+    cost REAL NOT NULL,
+    data TEXT NOT NULL
+);
+
+-- Rating 1 for thumbs up, -1 for thumbs down.
+CREATE TABLE IF NOT EXISTS feedback (
+    message_id INTEGER PRIMARY KEY REFERENCES messages (id) ON DELETE CASCADE,
+    rating INTEGER NOT NULL,
+    comment TEXT,
+    created TEXT NOT NULL
+);
+";
+
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+        upgrade(&conn)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Stores `conversation` with its `messages` and `feedback` in one
+    /// transaction, replacing what was stored under its id.
+    pub fn import(
+        &self,
+        conversation: &Conversation,
+        messages: &[Message],
+        feedback: &HashMap<String, Feedback>,
+    ) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM conversations WHERE id = ?1",
+            [&conversation.id],
+        )?;
+        save_conversation(&tx, conversation)?;
+        for message in messages {
+            insert_message(&tx, &conversation.id, message)?;
+        }
+        for (message, feedback) in feedback {
+            save_feedback(&tx, &conversation.id, message, feedback)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // A panic while holding the lock leaves no transaction open
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Columns added after the first version of the schema.
+const ADDED_COLUMNS: &[(&str, &str)] = &[
+    ("messages", "uid TEXT"),
+    ("messages", "parent_uid TEXT"),
+    ("messages", "cancelled INTEGER NOT NULL DEFAULT 0"),
+];
+
+/// Adds the columns missing from databases created by older versions.
+fn upgrade(conn: &Connection) -> Result<()> {
+    for (table, column) in ADDED_COLUMNS {
+        let name = column.split(' ').next().unwrap_or_default();
+        let exists: bool = conn.query_row(
+            &format!("SELECT COUNT(*) > 0 FROM pragma_table_info('{}') WHERE name = ?1", table),
+            [name],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {}", table, column))?;
+        }
+    }
+    Ok(())
+}
+
+fn conversation_from_row(row: &Row) -> rusqlite::Result<Conversation> {
+    Ok(Conversation {
+        id: row.get(0)?,
+        owner: row.get(1)?,
+        title: row.get(2)?,
+        created: row.get(3)?,
+        updated: row.get(4)?,
+    })
+}
+
+fn role_name(role: &Role) -> &'static str {
+    match role {
+        Role::System => "system",
+        Role::User => "user",
+        Role::Assistant => "assistant",
+    }
+}
+
+fn parse_role(role: &str) -> Result<Role> {
+    Ok(match role {
+        "system" => Role::System,
+        "user" => Role::User,
+        "assistant" => Role::Assistant,
+        _ => bail!("Unknown role {}", role),
+    })
+}
+
+impl HistoryStore for SqliteStore {
+    fn save_conversation(&self, conversation: &Conversation) -> Result<()> {
+        save_conversation(&self.conn(), conversation)
+    }
+
+    fn conversation(&self, id: &str) -> Result<Option<Conversation>> {
+        let conversation = self
+            .conn()
+            .query_row(
+                "SELECT id, owner, title, created, updated FROM conversations WHERE id = ?1",
+                [id],
+                conversation_from_row,
+            )
+            .optional()?;
+        Ok(conversation)
+    }
+
+    fn conversations(&self, owner: &str) -> Result<Vec<Conversation>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, owner, title, created, updated FROM conversations
+             WHERE owner = ?1 ORDER BY updated DESC",
+        )?;
+        let conversations = stmt
+            .query_map([owner], conversation_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(conversations)
+    }
+
+    fn delete_conversation(&self, id: &str) -> Result<()> {
+        self.conn()
+            .execute("DELETE FROM conversations WHERE id = ?1", [id])?;
+        Ok(())
+    }
+
+    fn history_exists(&self, id: &str) -> Result<bool> {
+        Ok(self.conversation(id)?.is_some())
+    }
+
+    fn messages(&self, id: &str) -> Result<Vec<Message<'static>>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT m.role, m.content, m.tokens, i.data, m.created, m.uid, m.parent_uid,
+                m.cancelled
+             FROM messages m
+             LEFT JOIN info i ON i.message_id = m.id
+             WHERE m.conversation_id = ?1 ORDER BY m.id",
+        )?;
+        let mut rows = stmt.query([id])?;
+        let mut messages = vec![];
+        while let Some(row) = rows.next()? {
+            let role: String = row.get(0)?;
+            let info: Option<String> = row.get(3)?;
+            let info: Option<Info> = match info {
+                Some(data) => Some(serde_json::from_str(&data)?),
+                None => None,
+            };
+            messages.push(Message {
+                msg: ChatCompletionRequestMessage {
+                    role: parse_role(&role)?,
+                    content: row.get(1)?,
+                    name: None,
+                },
+                tokens: row.get(2)?,
+                info,
+                created: row.get(4)?,
+                id: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+                parent: row.get(6)?,
+                cancelled: row.get(7)?,
+            });
+        }
+        Ok(messages)
+    }
+
+    fn append_message(&self, id: &str, message: &Message) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        insert_message(&tx, id, message)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn save_feedback(&self, id: &str, message: &str, feedback: &Feedback) -> Result<()> {
+        save_feedback(&self.conn(), id, message, feedback)
+    }
+
+    fn feedback(&self, id: &str) -> Result<HashMap<String, Feedback>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT m.uid, f.rating, f.comment, f.created,
+                    (SELECT COUNT(*) FROM messages p WHERE p.conversation_id = m.conversation_id AND p.id < m.id)
+             FROM feedback f JOIN messages m ON m.id = f.message_id
+             WHERE m.conversation_id = ?1",
+        )?;
+        let mut rows = stmt.query([id])?;
+        let mut feedback = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let uid: Option<String> = row.get(0)?;
+            let position: i64 = row.get(4)?;
+            let message = uid
+                .filter(|uid| !uid.is_empty())
+                .unwrap_or_else(|| position.to_string());
+            let rating = if row.get::<_, i64>(1)? > 0 {
+                Rating::Up
+            } else {
+                Rating::Down
+            };
+            feedback.insert(
+                message,
+                Feedback {
+                    rating,
+                    comment: row.get(2)?,
+                    created: row.get(3)?,
+                },
+            );
+        }
+        Ok(feedback)
+    }
+
+    fn entries(&self) -> Result<Vec<Entry>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT c.id, c.owner, c.title, c.created, c.updated,
+                    COUNT(m.id), COALESCE(SUM(LENGTH(m.content) + COALESCE(LENGTH(i.data), 0)), 0)
+             FROM conversations c
+             LEFT JOIN messages m ON m.conversation_id = c.id
+             LEFT JOIN info i ON i.message_id = m.id
+             GROUP BY c.id",
+        )?;
+        let entries = stmt
+            .query_map([], |row| {
+                let conversation = conversation_from_row(row)?;
+                Ok(Entry {
+                    id: conversation.id.clone(),
+                    modified: conversation.updated,
+                    conversation: Some(conversation),
+                    messages: row.get(5)?,
+                    size: row.get(6)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entries)
+    }
+}
+
+fn save_conversation(conn: &Connection, conversation: &Conversation) -> Result<()> {
+    conn.execute(
+        "INSERT INTO conversations (id, owner, title, created, updated)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT (id) DO UPDATE SET
+            owner = excluded.owner, title = excluded.title, updated = excluded.updated",
+        params![
+            conversation.id,
+            conversation.owner,
+            conversation.title,
+            conversation.created,
+            conversation.updated
+        ],
+    )?;
+    Ok(())
+}
+
+fn insert_message(conn: &Connection, id: &str, message: &Message) -> Result<()> {
+    conn.execute(
+        "INSERT INTO messages
+         (conversation_id, role, content, tokens, created, uid, parent_uid, cancelled)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            id,
+            role_name(&message.msg.role),
+            message.msg.content,
+            message.tokens,
+            message.created.unwrap_or_else(Utc::now),
+            message.id,
+            message.parent,
+            message.cancelled
+        ],
+    )
+    .map_err(|e| anyhow!("Couldn't store message of conversation {}: {}", id, e))?;
+    if let Some(info) = &message.info {
+        conn.execute(
+            "INSERT INTO info
+             (message_id, prompt_tokens, completion_tokens, embedding_tokens, cost, data)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                conn.last_insert_rowid(),
+                info.usage.prompt_tokens,
+                info.usage.completion_tokens,
+                info.usage.embedding_tokens,
+                info.cost,
+                serde_json::to_string(info)?
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn save_feedback(conn: &Connection, id: &str, message: &str, feedback: &Feedback) -> Result<()> {
+    let mut row_id = conn
+        .query_row(
+            "SELECT id FROM messages WHERE conversation_id = ?1 AND uid = ?2",
+            [id, message],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()?;
+    // Messages stored before branching are identified by their position
+    if let (None, Ok(position)) = (row_id, message.parse::<i64>()) {
+        row_id = conn
+            .query_row(
+                "SELECT id FROM messages WHERE conversation_id = ?1
+                 ORDER BY id LIMIT 1 OFFSET ?2",
+                params![id, position],
+                |row| row.get(0),
+            )
+            .optional()?;
+    }
+    let row_id = row_id.ok_or_else(|| anyhow!("No message {} in {}", message, id))?;
+    let rating = match feedback.rating {
+        Rating::Up => 1,
+        Rating::Down => -1,
+    };
+    conn.execute(
+        "INSERT INTO feedback (message_id, rating, comment, created) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (message_id) DO UPDATE SET
+            rating = excluded.rating, comment = excluded.comment, created = excluded.created",
+        params![row_id, rating, feedback.comment, feedback.created],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tables as created before messages had ids and could be cancelled.
+    const FIRST_SCHEMA: &str = "
+        CREATE TABLE conversations (
+            id TEXT PRIMARY KEY,
+            owner TEXT NOT NULL,
+            title TEXT NOT NULL,
+            created TEXT NOT NULL,
+            updated TEXT NOT NULL
+        );
+        CREATE TABLE messages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            conversation_id TEXT NOT NULL REFERENCES conversations (id) ON DELETE CASCADE,
+            role TEXT NOT NULL,
+            content TEXT NOT NULL,
+            tokens INTEGER NOT NULL,
+            created TEXT NOT NULL
+        );
+        INSERT INTO conversations VALUES ('c1', 'alice', 'Hi', '2023-05-01T10:00:00Z',
+            '2023-05-01T10:00:00Z');
+        INSERT INTO messages (conversation_id, role, content, tokens, created)
+            VALUES ('c1', 'user', 'Hello', 5, '2023-05-01T10:00:00Z');
+    ";
+
+    #[test]
+    fn open_upgrades_older_databases() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.db");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(FIRST_SCHEMA)
+            .unwrap();
+
+        let store = SqliteStore::open(&path).unwrap();
+        let messages = store.messages("c1").unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content(), "Hello");
+        assert_eq!(messages[0].id, "");
+        assert!(!messages[0].cancelled);
+
+        let mut answer = Message::new(Role::Assistant, "Hi Alice");
+        answer.id = "a1".to_string();
+        answer.cancelled = true;
+        store.append_message("c1", &answer).unwrap();
+        drop(store);
+
+        // Upgrading twice leaves the database as it is
+        let store = SqliteStore::open(&path).unwrap();
+        let messages = store.messages("c1").unwrap();
+        assert_eq!(messages[1].id, "a1");
+        assert!(messages[1].cancelled);
+    }
+}
@@ -0,0 +1,339 @@
+//! Optional audit trace of every turn, to find out why an answer was wrong.
+//!
+//! `history::Info` keeps what the UI shows; a trace record keeps everything
+//! the model saw and said: the question, the search queries, the retrieved
+//! candidates, every request with its parameters, the responses and the
+//! timings. Records are appended to a JSONL file which is rotated by size,
+//! with the matches of `trace.redact` replaced first.
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::bot::ToolCallInfo;
+use crate::config::{ChatMode, TraceConfig};
+use crate::embeddings::Candidate;
+use crate::usage::Usage;
+
+const REDACTED: &str = "[redacted]";
+
+/// What happened during one turn.
+#[derive(Debug, Serialize)]
+pub struct Trace {
+    time: DateTime<Utc>,
+    conversation: Option<String>,
+    mode: ChatMode,
+    question: String,
+    /// What the articles were searched with: the question, or the queries
+    /// of the model's searches.
+    queries: Vec<String>,
+    candidates: Vec<TracedCandidate>,
+    /// Every request to the chat model, the last one producing the answer.
+    calls: Vec<ModelCall>,
+    answer: Option<String>,
+    error: Option<String>,
+    /// Seconds spent in each stage timed with `timer!`.
+    timings: BTreeMap<&'static str, f64>,
+    seconds: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct TracedCandidate {
+    query: String,
+    filename: String,
+    score: f32,
+    tokens: usize,
+    /// Whether it was put into the context.
+    used: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ModelCall {
+    stage: &'static str,
+    model: String,
+    params: Value,
+    messages: Value,
+    response: Value,
+    usage: Usage,
+}
+
+impl Trace {
+    pub fn new(conversation: Option<&str>, mode: ChatMode, question: &str) -> Self {
+        Self {
+            time: Utc::now(),
+            conversation: conversation.map(str::to_string),
+            mode,
+            question: question.to_string(),
+            queries: vec![],
+            candidates: vec![],
+            calls: vec![],
+            answer: None,
+            error: None,
+            timings: BTreeMap::new(),
+            seconds: 0.0,
+        }
+    }
+
+    /// Records the articles retrieved for `query`.
+    pub fn candidates(&mut self, query: &str, candidates: &[Candidate]) {
+        self.queries.push(query.to_string());
+        // The context takes the candidates up to the first one not fitting
+        let mut used = true;
+        for candidate in candidates {
+            used &= candidate.fits;
+            self.candidates.push(TracedCandidate {
+                query: query.to_string(),
+                filename: candidate.filename.filename.to_string(),
+                score: candidate.filename.score,
+                tokens: candidate.tokens,
+                used,
+            });
+        }
+    }
+
+    /// Records the searches of the model. Articles which didn't fit are not
+    /// known.
+    pub fn tool_calls(&mut self, calls: &[ToolCallInfo]) {
+        for call in calls
+            .iter()
+            .filter(|call| call.name == "search_knowledge_base")
+        {
+            let query = serde_json::from_str::<Value>(&call.arguments)
+                .ok()
+                .and_then(|args| args.get("query")?.as_str().map(str::to_string))
+                .unwrap_or_else(|| call.arguments.clone());
+            for filename in &call.filenames {
+                self.candidates.push(TracedCandidate {
+                    query: query.clone(),
+                    filename: filename.filename.to_string(),
+                    score: filename.score,
+                    tokens: 0,
+                    used: true,
+                });
+            }
+            self.queries.push(query);
+        }
+    }
+
+    /// Records a request to the chat model and its response.
+    pub fn call(
+        &mut self,
+        stage: &'static str,
+        model: &str,
+        params: Value,
+        messages: &impl Serialize,
+        response: &impl Serialize,
+        usage: Usage,
+    ) {
+        self.calls.push(ModelCall {
+            stage,
+            model: model.to_string(),
+            params,
+            messages: serde_json::to_value(messages).unwrap_or_default(),
+            response: serde_json::to_value(response).unwrap_or_default(),
+            usage,
+        });
+    }
+
+    pub fn timing(&mut self, stage: &'static str, duration: Duration) {
+        *self.timings.entry(stage).or_default() += duration.as_secs_f64();
+    }
+
+    /// Records how the turn ended.
+    pub fn finish(&mut self, answer: Result<&str, &anyhow::Error>, duration: Duration) {
+        match answer {
+            Ok(answer) => self.answer = Some(answer.to_string()),
+            Err(e) => self.error = Some(e.to_string()),
+        }
+        self.seconds = duration.as_secs_f64();
+    }
+}
+
+/// The JSONL file traces are appended to, if enabled.
+pub struct TraceLog {
+    path: PathBuf,
+    redact: Vec<Regex>,
+    max_size: u64,
+    keep: usize,
+    /// Held while appending and rotating.
+    lock: Mutex<()>,
+}
+
+impl TraceLog {
+    /// `None` unless `trace.enabled`.
+    pub fn open(config: &TraceConfig) -> Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let redact = config
+            .redact
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .with_context(|| format!("trace.redact: invalid pattern `{}`", pattern))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Some(Self {
+            path: config.path.clone(),
+            redact,
+            max_size: config.max_size_mb * 1024 * 1024,
+            keep: config.keep,
+            lock: Mutex::new(()),
+        }))
+    }
+
+    pub fn write(&self, trace: &Trace) -> Result<()> {
+        let mut record = serde_json::to_value(trace)?;
+        if !self.redact.is_empty() {
+            redact(&mut record, &self.redact);
+        }
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+
+        let _lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let size = fs::metadata(&self.path).map_or(0, |m| m.len());
+        if self.max_size > 0 && size > 0 && size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .with_context(|| format!("Couldn't open {}", self.path.display()))?;
+        file.write_all(&line)?;
+        Ok(())
+    }
+
+    /// Moves `path` to `path.1`, `path.1` to `path.2` and so on, dropping
+    /// the oldest beyond `keep`.
+    fn rotate(&self) -> Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+            return Ok(());
+        }
+        let _ = fs::remove_file(rotated(&self.path, self.keep));
+        for n in (1..self.keep).rev() {
+            let from = rotated(&self.path, n);
+            if from.exists() {
+                fs::rename(&from, rotated(&self.path, n + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated(&self.path, 1))?;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    name.into()
+}
+
+/// Replaces the matches of `patterns` in every string of `value`.
+fn redact(value: &mut Value, patterns: &[Regex]) {
+    match value {
+        Value::String(s) => {
+            for pattern in patterns {
+                if let std::borrow::Cow::Owned(replaced) = pattern.replace_all(s, REDACTED) {
+                    *s = replaced;
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| redact(v, patterns)),
+        Value::Object(map) => map.values_mut().for_each(|v| redact(v, patterns)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn redact_replaces_matches_in_every_string() {
+        let patterns = [
+            Regex::new(r"sk-\w+").unwrap(),
+            Regex::new(r"\d{4}-\d{4}").unwrap(),
+        ];
+        let mut value = json!({
+            "question": "my key is sk-abc123",
+            "messages": [{ "content": "card 1234-5678, key sk-x" }, "nothing"],
+            "tokens": 1234,
+        });
+        redact(&mut value, &patterns);
+        assert_eq!(
+            value,
+            json!({
+                "question": "my key is [redacted]",
+                "messages": [{ "content": "card [redacted], key [redacted]" }, "nothing"],
+                "tokens": 1234,
+            })
+        );
+    }
+
+    #[test]
+    fn open_rejects_invalid_patterns() {
+        let config = TraceConfig {
+            enabled: true,
+            redact: vec!["(".to_string()],
+            ..TraceConfig::default()
+        };
+        assert!(TraceLog::open(&config).is_err());
+    }
+
+    fn log(dir: &tempfile::TempDir, keep: usize) -> TraceLog {
+        TraceLog {
+            path: dir.path().join("traces.jsonl"),
+            redact: vec![],
+            // Room for one trace only
+            max_size: 300,
+            keep,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn write(log: &TraceLog, question: &str) {
+        log.write(&Trace::new(None, ChatMode::Context, question))
+            .unwrap();
+    }
+
+    fn question(path: &Path) -> String {
+        let record: Value = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        record["question"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn write_rotates_and_keeps_the_newest_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = log(&dir, 2);
+        for q in ["q1", "q2", "q3", "q4"] {
+            write(&log, q);
+        }
+        assert_eq!(question(&log.path), "q4");
+        assert_eq!(question(&rotated(&log.path, 1)), "q3");
+        assert_eq!(question(&rotated(&log.path, 2)), "q2");
+        assert!(!rotated(&log.path, 3).exists());
+    }
+
+    #[test]
+    fn rotate_without_keep_starts_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = log(&dir, 0);
+        write(&log, "q1");
+        write(&log, "q2");
+        assert_eq!(question(&log.path), "q2");
+        assert!(!rotated(&log.path, 1).exists());
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    ops::AddAssign,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use async_openai::types;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Tokens consumed by OpenAI calls.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub embedding_tokens: u64,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens + self.embedding_tokens
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.embedding_tokens += other.embedding_tokens;
+    }
+}
+
+impl From<&types::Usage> for Usage {
+    fn from(usage: &types::Usage) -> Self {
+        Self {
+            prompt_tokens: usage.prompt_tokens.into(),
+            completion_tokens: usage.completion_tokens.into(),
+            embedding_tokens: 0,
+        }
+    }
+}
+
+impl From<&types::EmbeddingUsage> for Usage {
+    fn from(usage: &types::EmbeddingUsage) -> Self {
+        Self {
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            embedding_tokens: usage.prompt_tokens.into(),
+        }
+    }
+}
+
+/// Price in USD per 1000 tokens.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Prices(pub HashMap<String, ModelPrice>);
+
+impl Default for Prices {
+    fn default() -> Self {
+        Self(HashMap::from([
+            (
+                "gpt-3.5-turbo".to_string(),
+                ModelPrice {
+                    prompt: 0.0015,
+                    completion: 0.002,
+                },
+            ),
+            (
+                "text-embedding-ada-002".to_string(),
+                ModelPrice {
+                    prompt: 0.0001,
+                    completion: 0.0,
+                },
+            ),
+        ]))
+    }
+}
+
+impl Prices {
+    /// Cost of `usage` in USD. Models missing from the table are free.
+    pub fn cost(&self, usage: &Usage, chat_model: &str, embedding_model: &str) -> f64 {
+        let chat = self.0.get(chat_model).copied().unwrap_or_default();
+        let embedding = self.0.get(embedding_model).copied().unwrap_or_default();
+        (usage.prompt_tokens as f64 * chat.prompt
+            + usage.completion_tokens as f64 * chat.completion
+            + usage.embedding_tokens as f64 * embedding.prompt)
+            / 1000.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Totals {
+    pub requests: u64,
+    pub usage: Usage,
+    pub cost: f64,
+}
+
+impl Totals {
+    fn add(&mut self, usage: Usage, cost: f64) {
+        self.requests += 1;
+        self.usage += usage;
+        self.cost += cost;
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    time: DateTime<Utc>,
+    session: String,
+    usage: Usage,
+    cost: f64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub total: Totals,
+    pub days: BTreeMap<String, Totals>,
+    pub sessions: HashMap<String, Totals>,
+}
+
+/// Aggregates usage per session and per day. Every record is appended
+/// to a JSONL log, which is replayed on startup.
+pub struct Ledger {
+    path: PathBuf,
+    summary: Mutex<Summary>,
+}
+
+impl Ledger {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut summary = Summary::default();
+        if path.exists() {
+            for (idx, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+                // A crash while appending may leave a partial last line
+                match serde_json::from_str::<Record>(&line?) {
+                    Ok(record) => add_record(&mut summary, &record),
+                    Err(e) => warn!(
+                        "{}:{}: skipping invalid record: {}",
+                        path.display(),
+                        idx + 1,
+                        e
+                    ),
+                }
+            }
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            summary: Mutex::new(summary),
+        })
+    }
+
+    /// Records one turn and returns the updated totals of the session.
+    pub fn record(&self, session: &str, usage: Usage, cost: f64) -> Result<Totals> {
+        let record = Record {
+            time: Utc::now(),
+            session: session.to_string(),
+            usage,
+            cost,
+        };
+
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+
+        // Appending under the lock keeps records of concurrent turns whole
+        let mut summary = self.summary.lock().unwrap();
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)?
+            .write_all(&line)?;
+        add_record(&mut summary, &record);
+        Ok(summary.sessions[session])
+    }
+
+    pub fn summary(&self) -> Summary {
+        let summary = self.summary.lock().unwrap();
+        Summary {
+            total: summary.total,
+            days: summary.days.clone(),
+            sessions: summary.sessions.clone(),
+        }
+    }
+}
+
+fn add_record(summary: &mut Summary, record: &Record) {
+    let day = record.time.format("%Y-%m-%d").to_string();
+    summary.total.add(record.usage, record.cost);
+    summary.days.entry(day).or_default().add(record.usage, record.cost);
+    summary
+        .sessions
+        .entry(record.session.clone())
+        .or_default()
+        .add(record.usage, record.cost);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64, embedding_tokens: u64) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            embedding_tokens,
+        }
+    }
+
+    #[test]
+    fn cost_uses_the_prices_of_both_models() {
+        let price = |prompt, completion| ModelPrice { prompt, completion };
+        let prices = Prices(HashMap::from([
+            ("chat".to_string(), price(1.0, 2.0)),
+            ("embedding".to_string(), price(0.5, 100.0)),
+        ]));
+        let cost = prices.cost(&usage(1000, 500, 2000), "chat", "embedding");
+        assert!((cost - 3.0).abs() < 1e-9, "{}", cost);
+
+        // Unknown models are free
+        assert_eq!(prices.cost(&usage(1000, 500, 2000), "other", "other"), 0.0);
+        let cost = prices.cost(&usage(1000, 500, 2000), "other", "embedding");
+        assert!((cost - 1.0).abs() < 1e-9, "{}", cost);
+    }
+
+    #[test]
+    fn ledger_replays_records_and_skips_invalid_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("usage.jsonl");
+        let ledger = Ledger::open(&path).unwrap();
+        ledger.record("alice", usage(10, 5, 0), 0.5).unwrap();
+        let totals = ledger.record("alice", usage(1, 1, 1), 0.25).unwrap();
+        assert_eq!(totals.requests, 2);
+        assert_eq!(totals.usage, usage(11, 6, 1));
+        ledger.record("bob", usage(1, 0, 0), 0.0).unwrap();
+
+        // A partial last line, as left by a crash
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"time\":").unwrap();
+        let summary = Ledger::open(&path).unwrap().summary();
+        assert_eq!(summary.total.requests, 3);
+        assert_eq!(summary.sessions["alice"].cost, 0.75);
+        assert_eq!(summary.sessions["bob"].requests, 1);
+        assert_eq!(summary.days.len(), 1);
+    }
+}
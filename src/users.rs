@@ -0,0 +1,237 @@
+//! Local user accounts, kept in the JSON file `paths.users`.
+//!
+//! Users log in with a password, hashed with argon2, or through the OIDC
+//! provider of `[auth.oidc]`, which creates their account on first login.
+//! The server sees the changes of `gpt-rs user` as soon as the file changes.
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::json_file::{JsonFile, Locked};
+use crate::session::random_id;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    /// Owner of the user's conversations.
+    pub id: String,
+    pub username: String,
+    /// PHC string of the argon2 hash; `None` for accounts created by OIDC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oidc: Option<OidcIdentity>,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OidcIdentity {
+    pub issuer: String,
+    pub subject: String,
+}
+
+impl User {
+    /// Whether the account is one of `admins`. Only password accounts count,
+    /// since an OIDC account may get a username the provider chose.
+    pub fn is_admin(&self, admins: &[String]) -> bool {
+        self.password_hash.is_some() && admins.contains(&self.username)
+    }
+}
+
+pub struct Users {
+    users: JsonFile<Vec<User>>,
+}
+
+impl Users {
+    /// Loads the accounts of `path`, which is created on the first change.
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            users: JsonFile::open(path, "users")?,
+        })
+    }
+
+    fn users(&self) -> Locked<'_, Vec<User>> {
+        self.users.lock()
+    }
+
+    pub fn list(&self) -> Vec<User> {
+        self.users().clone()
+    }
+
+    pub fn get(&self, id: &str) -> Option<User> {
+        self.users().iter().find(|u| u.id == id).cloned()
+    }
+
+    pub fn by_username(&self, username: &str) -> Option<User> {
+        self.users()
+            .iter()
+            .find(|u| u.username == username)
+            .cloned()
+    }
+
+    pub fn add(&self, username: &str, password: &str) -> Result<User> {
+        let username = username.trim();
+        if username.is_empty() {
+            bail!("The username must not be empty");
+        }
+        let user = User {
+            id: random_id(),
+            username: username.to_string(),
+            password_hash: Some(hash_password(password)?),
+            oidc: None,
+            created: Utc::now(),
+        };
+        let mut users = self.users();
+        if users.iter().any(|u| u.username == user.username) {
+            bail!("User {} already exists", user.username);
+        }
+        users.push(user.clone());
+        users.save()?;
+        Ok(user)
+    }
+
+    pub fn set_password(&self, username: &str, password: &str) -> Result<()> {
+        let hash = hash_password(password)?;
+        let mut users = self.users();
+        let user = users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .ok_or_else(|| anyhow!("No user {}", username))?;
+        user.password_hash = Some(hash);
+        users.save()
+    }
+
+    /// Removes the account; its conversations are left to the retention
+    /// policy.
+    pub fn remove(&self, username: &str) -> Result<User> {
+        let mut users = self.users();
+        let idx = users
+            .iter()
+            .position(|u| u.username == username)
+            .ok_or_else(|| anyhow!("No user {}", username))?;
+        let user = users.remove(idx);
+        users.save()?;
+        Ok(user)
+    }
+
+    /// The user with `username` if `password` matches. Hashing takes a
+    /// while, so async code should call it with `spawn_blocking`.
+    pub fn verify(&self, username: &str, password: &str) -> Option<User> {
+        let user = self.by_username(username)?;
+        let hash = PasswordHash::new(user.password_hash.as_deref()?).ok()?;
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .ok()?;
+        Some(user)
+    }
+
+    /// The user logged in through OIDC, created on first login. The username
+    /// is the preferred one of the provider unless a local account already
+    /// uses it.
+    pub fn oidc_user(&self, identity: OidcIdentity, username: &str) -> Result<User> {
+        let mut users = self.users();
+        if let Some(user) = users.iter().find(|u| u.oidc.as_ref() == Some(&identity)) {
+            return Ok(user.clone());
+        }
+        let username = if users.iter().any(|u| u.username == username) {
+            format!("{}@{}", identity.subject, identity.issuer)
+        } else {
+            username.to_string()
+        };
+        let user = User {
+            id: random_id(),
+            username,
+            password_hash: None,
+            oidc: Some(identity),
+            created: Utc::now(),
+        };
+        users.push(user.clone());
+        users.save()?;
+        Ok(user)
+    }
+}
+
+fn hash_password(password: &str) -> Result<String> {
+    if password.is_empty() {
+        bail!("The password must not be empty");
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Couldn't hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(subject: &str) -> OidcIdentity {
+        OidcIdentity {
+            issuer: "https://id.example.com".to_string(),
+            subject: subject.to_string(),
+        }
+    }
+
+    #[test]
+    fn verify_checks_the_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let users = Users::open(&dir.path().join("users.json")).unwrap();
+        let alice = users.add(" alice ", "secret").unwrap();
+        assert_eq!(alice.username, "alice");
+
+        assert_eq!(users.verify("alice", "secret").unwrap().id, alice.id);
+        assert!(users.verify("alice", "wrong").is_none());
+        assert!(users.verify("bob", "secret").is_none());
+
+        users.set_password("alice", "changed").unwrap();
+        assert!(users.verify("alice", "secret").is_none());
+        assert!(users.verify("alice", "changed").is_some());
+
+        assert!(users.add("alice", "other").is_err());
+        assert!(users.add("bob", "").is_err());
+        assert!(users.add(" ", "secret").is_err());
+    }
+
+    #[test]
+    fn oidc_users_are_created_once_and_have_no_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let users = Users::open(&dir.path().join("users.json")).unwrap();
+        let created = users.oidc_user(identity("42"), "carol").unwrap();
+        assert_eq!(created.username, "carol");
+        assert!(created.password_hash.is_none());
+
+        let again = users.oidc_user(identity("42"), "renamed").unwrap();
+        assert_eq!(again.id, created.id);
+        assert_eq!(users.list().len(), 1);
+        assert!(users.verify("carol", "").is_none());
+    }
+
+    #[test]
+    fn oidc_users_dont_take_local_usernames() {
+        let dir = tempfile::tempdir().unwrap();
+        let users = Users::open(&dir.path().join("users.json")).unwrap();
+        let alice = users.add("alice", "secret").unwrap();
+        let other = users.oidc_user(identity("7"), "alice").unwrap();
+        assert_ne!(other.id, alice.id);
+        assert_eq!(other.username, "7@https://id.example.com");
+        assert_eq!(users.by_username("alice").unwrap().id, alice.id);
+    }
+
+    #[test]
+    fn only_password_accounts_are_admins() {
+        let dir = tempfile::tempdir().unwrap();
+        let users = Users::open(&dir.path().join("users.json")).unwrap();
+        let admins = ["alice".to_string(), "carol".to_string()];
+        assert!(users.add("alice", "secret").unwrap().is_admin(&admins));
+        assert!(!users.add("bob", "secret").unwrap().is_admin(&admins));
+        let carol = users.oidc_user(identity("42"), "carol").unwrap();
+        assert_eq!(carol.username, "carol");
+        assert!(!carol.is_admin(&admins));
+    }
+}
@@ -1,42 +1,317 @@
+//! The chat websocket and its JSON protocol.
+//!
+//! Every frame is an object with the protocol version `v` and a `type`. The
+//! client may add a `request` id to a frame; the frames the server sends in
+//! response carry it, ending with an `ack` once the command is done or an
+//! `error` if it failed.
+//!
+//! Frames with messages carry a sequence number `seq`. A client which
+//! reconnects sends `resume` with the last one it saw and gets the messages
+//! it missed.
 use anyhow::Result;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::ops::ControlFlow;
-//use tracing::{warn,debug};
-use std::println as info;
-use std::println as error;
-use std::println as warn;
-use std::println as debug;
+use tracing::{debug, warn};
 
+use axum::extract::ws::{close_code, CloseFrame, Message as WsMessage, WebSocket as AxumWebSocket};
+use tokio::time::{self, Duration, Instant, Interval, MissedTickBehavior};
 
-use axum::extract::ws::{Message as WsMessage, WebSocket as AxumWebSocket};
+use crate::history::Rating;
+use crate::html::Message as HTMLMsg;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+fn default_version() -> u32 {
+    PROTOCOL_VERSION
+}
+
+/// A frame sent by the client.
+#[derive(Debug, Deserialize)]
+pub struct ClientFrame {
+    /// Clients predating versions send none.
+    #[serde(default = "default_version")]
+    pub v: u32,
+    #[serde(default)]
+    pub request: Option<String>,
+    #[serde(flatten)]
+    pub command: ClientCommand,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientCommand {
+    Message {
+        text: String,
+    },
+    /// Ask the edited version of question `id` in a new branch.
+    Edit {
+        id: String,
+        text: String,
+    },
+    /// Answer the question of answer `id` again in a new branch.
+    Regenerate {
+        id: String,
+    },
+    /// Show the branch containing message `id`.
+    Switch {
+        id: String,
+    },
+    /// Rate answer `id`.
+    Feedback {
+        id: String,
+        rating: Rating,
+        #[serde(default)]
+        comment: Option<String>,
+    },
+    /// Stop the answer being written.
+    #[serde(alias = "stop")]
+    Cancel,
+    /// Sent after reconnecting: replay what was added after message `seq`.
+    Resume { seq: usize },
+    Settings(Settings),
+}
+
+/// Options of a socket, replaced by the `settings` command.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Settings {
+    /// Send answers piece by piece in `delta` frames while they are written.
+    #[serde(default)]
+    pub stream: bool,
+}
+
+impl ClientCommand {
+    /// The `type` of the command, to log it without its text.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientCommand::Message { .. } => "message",
+            ClientCommand::Edit { .. } => "edit",
+            ClientCommand::Regenerate { .. } => "regenerate",
+            ClientCommand::Switch { .. } => "switch",
+            ClientCommand::Feedback { .. } => "feedback",
+            ClientCommand::Cancel => "cancel",
+            ClientCommand::Resume { .. } => "resume",
+            ClientCommand::Settings(_) => "settings",
+        }
+    }
+}
+
+impl ClientFrame {
+    /// Parses a frame, or returns the error frame to send back. Text which
+    /// isn't a JSON object is a question, as sent by clients predating
+    /// commands.
+    pub fn parse(text: &str) -> Result<Self, Box<ServerFrame>> {
+        let Ok(Value::Object(object)) = serde_json::from_str::<Value>(text) else {
+            return Ok(ClientFrame {
+                v: PROTOCOL_VERSION,
+                request: None,
+                command: ClientCommand::Message {
+                    text: text.to_string(),
+                },
+            });
+        };
+        let request = object
+            .get("request")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let v = object
+            .get("v")
+            .map_or(Some(PROTOCOL_VERSION.into()), Value::as_u64);
+        if v != Some(PROTOCOL_VERSION.into()) {
+            let message = format!(
+                "Unsupported protocol version {}, the server speaks version {}",
+                object.get("v").unwrap_or(&Value::Null),
+                PROTOCOL_VERSION
+            );
+            return Err(Box::new(ServerFrame::error(
+                request,
+                ErrorCode::UnsupportedVersion,
+                message,
+            )));
+        }
+        serde_json::from_value(Value::Object(object)).map_err(|e| {
+            let message = format!("Invalid command: {}", e);
+            Box::new(ServerFrame::error(
+                request,
+                ErrorCode::InvalidCommand,
+                message,
+            ))
+        })
+    }
+}
+
+/// A frame sent by the server.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerFrame {
+    pub v: u32,
+    /// The `request` of the client frame this one responds to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request: Option<String>,
+    /// Number of messages stored in the conversation, on frames adding or
+    /// showing messages. Clients resume from the highest one they have seen.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<usize>,
+    #[serde(flatten)]
+    pub event: ServerEvent,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// A question added to the conversation.
+    User {
+        message: HTMLMsg,
+    },
+    /// An answer added to the conversation, complete or cancelled.
+    Assistant {
+        message: HTMLMsg,
+    },
+    /// The next piece of the answer being written, with `stream` on.
+    Delta {
+        text: String,
+    },
+    /// Replaces the messages shown with the active branch.
+    History {
+        messages: Vec<HTMLMsg>,
+    },
+    Status {
+        state: State,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+        /// Seconds to wait before sending again, when rate limited.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+    },
+    /// The command of `request` is done.
+    Ack,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Answering,
+    Idle,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame is not a command of this protocol version.
+    InvalidCommand,
+    UnsupportedVersion,
+    RateLimited,
+    /// The command couldn't be carried out.
+    Failed,
+}
+
+impl ServerFrame {
+    pub fn new(request: Option<String>, event: ServerEvent) -> Self {
+        Self {
+            v: PROTOCOL_VERSION,
+            request,
+            seq: None,
+            event,
+        }
+    }
+
+    pub fn with_seq(self, seq: usize) -> Self {
+        Self {
+            seq: Some(seq),
+            ..self
+        }
+    }
+
+    pub fn error(request: Option<String>, code: ErrorCode, message: String) -> Self {
+        Self::new(
+            request,
+            ServerEvent::Error {
+                code,
+                message,
+                retry_after: None,
+            },
+        )
+    }
+}
+
+impl ServerEvent {
+    /// The event adding `message` to the conversation.
+    pub fn message(message: HTMLMsg) -> Self {
+        match message.typ.as_str() {
+            "user" => ServerEvent::User { message },
+            _ => ServerEvent::Assistant { message },
+        }
+    }
+}
 
 pub struct WebSocket {
     socket: AxumWebSocket,
+    heartbeat: Duration,
+    idle_timeout: Duration,
+    /// When the client last sent a frame, pongs included.
+    last_seen: Instant,
+    ticks: Interval,
 }
 
 impl WebSocket {
-    pub async fn initiate(mut socket: AxumWebSocket) -> Result<Self> {
+    /// Wraps `socket`, pinging the client after `heartbeat` without frames
+    /// from it and closing the socket after `idle_timeout`.
+    pub async fn initiate(
+        mut socket: AxumWebSocket,
+        heartbeat: Duration,
+        idle_timeout: Duration,
+    ) -> Result<Self> {
         //send a ping (unsupported by some browsers) just to kick things off and get a response
         socket.send(WsMessage::Ping(vec![1, 2, 3])).await?;
         debug!("Pinged ...");
 
-        Ok(Self { socket })
+        let mut ticks = time::interval_at(Instant::now() + heartbeat, heartbeat);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Ok(Self {
+            socket,
+            heartbeat,
+            idle_timeout,
+            last_seen: Instant::now(),
+            ticks,
+        })
     }
 
+    /// The next text frame, `None` once the socket is closed. Cancel safe.
     pub async fn next(&mut self) -> Option<String> {
-        while let Some(msg) = self.socket.recv().await {
-            if let Ok(msg) = msg {
-                match process_message(msg) {
-                    ControlFlow::Continue(Some(s)) => return Some(s),
-                    ControlFlow::Continue(None) => continue,
-                    ControlFlow::Break(_) => return None,
+        loop {
+            tokio::select! {
+                msg = self.socket.recv() => {
+                    let Some(Ok(msg)) = msg else {
+                        warn!("client  abruptly disconnected");
+                        return None;
+                    };
+                    self.last_seen = Instant::now();
+                    match process_message(msg) {
+                        ControlFlow::Continue(Some(s)) => return Some(s),
+                        ControlFlow::Continue(None) => continue,
+                        ControlFlow::Break(_) => return None,
+                    }
+                }
+                _ = self.ticks.tick() => {
+                    let quiet = self.last_seen.elapsed();
+                    if quiet >= self.idle_timeout {
+                        warn!("closing websocket idle for {:?}", quiet);
+                        let close = CloseFrame {
+                            code: close_code::AWAY,
+                            reason: "idle timeout".into(),
+                        };
+                        let _ = self.socket.send(WsMessage::Close(Some(close))).await;
+                        return None;
+                    }
+                    if quiet >= self.heartbeat
+                        && self.socket.send(WsMessage::Ping(vec![])).await.is_err()
+                    {
+                        return None;
+                    }
                 }
-            } else {
-                warn!("client  abruptly disconnected");
-                return None;
             }
         }
-        None
     }
 
     pub async fn send<S>(&mut self, msg: S) -> Result<()>
@@ -53,11 +328,11 @@ impl WebSocket {
 fn process_message(msg: WsMessage) -> ControlFlow<(), Option<String>> {
     match msg {
         WsMessage::Text(t) => {
-            debug!(">>>  sent str: {:?}", t);
+            debug!(">>> sent text of {} bytes", t.len());
             ControlFlow::Continue(Some(t))
         }
         WsMessage::Binary(d) => {
-            debug!(">>> sent {} bytes: {:?}", d.len(), d);
+            debug!(">>> sent {} binary bytes", d.len());
             ControlFlow::Continue(None)
         }
         WsMessage::Close(c) => {
@@ -85,3 +360,28 @@ fn process_message(msg: WsMessage) -> ControlFlow<(), Option<String>> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_names_are_their_types() {
+        for command in [
+            r#"{"type": "message", "text": "Hi"}"#,
+            r#"{"type": "edit", "id": "1", "text": "Hi"}"#,
+            r#"{"type": "regenerate", "id": "1"}"#,
+            r#"{"type": "switch", "id": "1"}"#,
+            r#"{"type": "feedback", "id": "1", "rating": "up"}"#,
+            r#"{"type": "cancel"}"#,
+            r#"{"type": "resume", "seq": 1}"#,
+            r#"{"type": "settings", "stream": true}"#,
+        ] {
+            let frame = ClientFrame::parse(command).unwrap();
+            let tag = format!(r#""type": "{}""#, frame.command.name());
+            assert!(command.contains(&tag), "{}", command);
+        }
+        let question = ClientFrame::parse("What is Rust?").unwrap();
+        assert_eq!(question.command.name(), "message");
+    }
+}